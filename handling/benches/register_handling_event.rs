@@ -0,0 +1,231 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use handling::application::service::{Service, ServiceImpl};
+use handling::domain::handling::{
+    Cargo, HandlingEvent, HandlingEventCriteria, HandlingEventFactoryImpl, HandlingEventRepository,
+    HandlingEventType, HandlingHistory, TrackingID,
+};
+use handling::domain::itinerary::Itinerary;
+use handling::domain::location::{Location, UNLocode};
+use handling::domain::voyage::{Voyage, VoyageNumber};
+use handling::domain::{location, voyage, Page, PageRequest, Repository, Version, Versioned};
+use handling::infrastructure::inmem_repository::{InmemRepository, DEFAULT_SHARDS};
+use handling::Error;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+// Terminals registering events concurrently, each for its own cargo.
+const TERMINALS: usize = 16;
+const EVENTS_PER_TERMINAL: usize = 50;
+
+// MutexRepository runs every call of the repository it wraps under one
+// mutex, reads included, as the repository did before it was sharded. It is
+// the baseline of the sharded variants.
+#[derive(Clone)]
+struct MutexRepository<R> {
+    inner: R,
+    lock: Arc<Mutex<()>>,
+}
+
+impl<R> MutexRepository<R> {
+    fn new(inner: R) -> Self {
+        MutexRepository {
+            inner,
+            lock: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V, R> Repository<K, V> for MutexRepository<R>
+where
+    K: Eq + Hash + Display + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    R: Repository<K, V>,
+{
+    async fn store(&self, id: K, v: &V) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.store(id, v).await
+    }
+
+    async fn find(&self, id: K) -> Result<V, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.find(id).await
+    }
+
+    async fn find_versioned(&self, id: K) -> Result<Versioned<V>, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.find_versioned(id).await
+    }
+
+    async fn store_if_version(
+        &self,
+        id: K,
+        v: &V,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.store_if_version(id, v, expected).await
+    }
+
+    async fn find_all(&self) -> Result<Vec<V>, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.find_all().await
+    }
+
+    async fn exists(&self, id: K) -> Result<bool, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.exists(id).await
+    }
+
+    async fn delete(&self, id: K) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.delete(id).await
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<V>, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.find_page(page).await
+    }
+}
+
+#[async_trait]
+impl<R: HandlingEventRepository> HandlingEventRepository for MutexRepository<R> {
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.append(e).await
+    }
+
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.append_if_version(e, expected).await
+    }
+
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.query_handling_history(id).await
+    }
+
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error> {
+        let _guard = self.lock.lock().await;
+        self.inner.find_handling_events(criteria, page).await
+    }
+}
+
+fn tracking_id(terminal: usize) -> TrackingID {
+    format!("{:03}", terminal)
+}
+
+async fn new_service<C, V, L, H>(
+    cargos: C,
+    voyages: V,
+    locations: L,
+    handling_events: H,
+) -> Arc<ServiceImpl<H, HandlingEventFactoryImpl<C, V, L>>>
+where
+    C: Repository<TrackingID, Cargo>,
+    V: Repository<VoyageNumber, Voyage>,
+    L: Repository<UNLocode, Location>,
+    H: HandlingEventRepository,
+{
+    for terminal in 0..TERMINALS {
+        let cargo = Cargo {
+            tracking_id: tracking_id(terminal),
+            origin: "SESTO".to_string(),
+            destination: "AUMEL".to_string(),
+            arrival_deadline: Utc::now(),
+            itinerary: Itinerary::default(),
+        };
+        cargos
+            .store(cargo.tracking_id.clone(), &cargo)
+            .await
+            .unwrap();
+    }
+    voyage::populate_repository(&voyages).await.unwrap();
+    location::store_sample_locations(&locations).await.unwrap();
+    let event_factory = HandlingEventFactoryImpl::new(cargos, voyages, locations);
+    Arc::new(ServiceImpl::new_service(handling_events, event_factory))
+}
+
+// Each terminal receives its cargo and then clears it through customs over
+// and over, which the cargo lifecycle allows any number of times.
+async fn register<S: Service + Send + Sync + 'static>(srv: Arc<S>) {
+    let terminals: Vec<_> = (0..TERMINALS)
+        .map(|terminal| {
+            let srv = srv.clone();
+            tokio::spawn(async move {
+                for n in 0..EVENTS_PER_TERMINAL {
+                    let event_type = match n {
+                        0 => HandlingEventType::Receive,
+                        _ => HandlingEventType::Customs,
+                    };
+                    srv.register_handling_event(
+                        Utc::now(),
+                        tracking_id(terminal),
+                        "".to_string(),
+                        "SESTO".to_string(),
+                        event_type,
+                    )
+                    .await
+                    .unwrap();
+                }
+            })
+        })
+        .collect();
+    for terminal in terminals {
+        terminal.await.unwrap();
+    }
+}
+
+// The mutex variant serializes every repository call. A single shard still
+// lets reads of the same map run together, the default spreads writes over
+// DEFAULT_SHARDS locks.
+fn concurrent_registration(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("register_handling_event");
+    group.throughput(Throughput::Elements(
+        (TERMINALS * EVENTS_PER_TERMINAL) as u64,
+    ));
+    group.bench_function("mutex", |b| {
+        b.iter_batched(
+            || {
+                rt.block_on(new_service(
+                    MutexRepository::new(InmemRepository::with_shards(1)),
+                    MutexRepository::new(InmemRepository::with_shards(1)),
+                    MutexRepository::new(InmemRepository::with_shards(1)),
+                    MutexRepository::new(InmemRepository::with_shards(1)),
+                ))
+            },
+            |srv| rt.block_on(register(srv)),
+            BatchSize::SmallInput,
+        )
+    });
+    for &shards in &[1, DEFAULT_SHARDS] {
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, &shards| {
+            b.iter_batched(
+                || {
+                    rt.block_on(new_service(
+                        InmemRepository::with_shards(shards),
+                        InmemRepository::with_shards(shards),
+                        InmemRepository::with_shards(shards),
+                        InmemRepository::with_shards(shards),
+                    ))
+                },
+                |srv| rt.block_on(register(srv)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_registration);
+criterion_main!(benches);
@@ -7,6 +7,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "proto/handling.proto",
                 "proto/handling_events.proto",
                 "proto/booking_events.proto",
+                "proto/google/rpc/status.proto",
+                "proto/google/rpc/error_details.proto",
             ],
             &["proto"],
         )?;
@@ -0,0 +1,135 @@
+use crate::application::pb::{OrderingKey, TypeName};
+use chrono::prelude::*;
+
+/// The CloudEvents version the attributes follow.
+pub const SPEC_VERSION: &str = "1.0";
+/// Where the messages of the handling service come from.
+pub const SOURCE: &str = "/shipping/handling";
+/// The version of the published messages, raised on breaking changes of the
+/// protobuf definitions.
+pub const SCHEMA_VERSION: u32 = 1;
+pub const CONTENT_TYPE: &str = "application/protobuf";
+
+/// Envelope is the metadata an integration event travels with, after the
+/// CloudEvents attributes. Brokers carry it in the headers of a message,
+/// beside the protobuf payload.
+///
+/// Messages of publishers that set no metadata only have their type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// Unique per published message, kept by redeliveries and retries.
+    pub id: Option<String>,
+    /// The service that published the message.
+    pub source: Option<String>,
+    /// The `TypeName` of the message.
+    pub r#type: String,
+    pub time: Option<DateTime<Utc>>,
+    /// The tracking ID of the cargo the event is about.
+    pub subject: Option<String>,
+    /// Ties together the messages that follow from one another.
+    pub correlation_id: Option<String>,
+    pub schema_version: Option<u32>,
+    /// Orders the events of the subject from one source, after the
+    /// CloudEvents sequence extension. A later event has a greater number.
+    pub sequence: Option<u64>,
+}
+
+impl Envelope {
+    /// Returns the envelope of the message `id` published now. The tracking
+    /// ID is the correlation ID, which follows a cargo from booking through
+    /// handling to tracking.
+    pub fn new<E: TypeName + OrderingKey>(e: &E, id: String) -> Self {
+        Envelope {
+            id: Some(id),
+            source: Some(SOURCE.to_string()),
+            r#type: E::name().to_string(),
+            time: Some(Utc::now()),
+            subject: Some(e.ordering_key().to_string()),
+            correlation_id: Some(e.ordering_key().to_string()),
+            schema_version: Some(SCHEMA_VERSION),
+            sequence: None,
+        }
+    }
+
+    /// Returns the envelope of a message that carries no metadata.
+    pub fn of_type(kind: &str) -> Self {
+        Envelope {
+            id: None,
+            source: None,
+            r#type: kind.to_string(),
+            time: None,
+            subject: None,
+            correlation_id: None,
+            schema_version: None,
+            sequence: None,
+        }
+    }
+
+    /// Returns the attributes to set on a message, by their CloudEvents
+    /// names.
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attributes = vec![
+            ("specversion", SPEC_VERSION.to_string()),
+            ("type", self.r#type.clone()),
+        ];
+        let optional = [
+            ("id", self.id.clone()),
+            ("source", self.source.clone()),
+            ("time", self.time.map(|time| time.to_rfc3339())),
+            ("subject", self.subject.clone()),
+            ("correlationid", self.correlation_id.clone()),
+            (
+                "schemaversion",
+                self.schema_version.map(|version| version.to_string()),
+            ),
+            (
+                "sequence",
+                self.sequence.map(|sequence| sequence.to_string()),
+            ),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                attributes.push((name, value));
+            }
+        }
+        attributes
+    }
+
+    /// Reads the envelope of a message of type `kind` from its attributes,
+    /// looked up by their CloudEvents names. Malformed attributes are
+    /// ignored.
+    pub fn from_attributes<A>(kind: &str, attribute: A) -> Self
+    where
+        A: Fn(&str) -> Option<String>,
+    {
+        Envelope {
+            id: attribute("id"),
+            source: attribute("source"),
+            r#type: kind.to_string(),
+            time: attribute("time")
+                .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+            subject: attribute("subject"),
+            correlation_id: attribute("correlationid"),
+            schema_version: attribute("schemaversion").and_then(|version| version.parse().ok()),
+            sequence: attribute("sequence").and_then(|sequence| sequence.parse().ok()),
+        }
+    }
+}
+
+/// Returns a random UUID, the id of a new message. A message published again
+/// keeps the id it was first given.
+pub fn new_id() -> String {
+    // Version 4, variant 1.
+    let bits =
+        (rand::random::<u128>() & !(0xf000 << 64) & !(0xc << 60)) | (0x4000 << 64) | (0x8 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -4,11 +4,17 @@ use crate::domain::location::UNLocode;
 use crate::domain::voyage::VoyageNumber;
 use crate::Error;
 use chrono::prelude::*;
-use std::convert::{TryFrom, TryInto};
-use std::time::SystemTime;
+use prost::Message;
+use prost_types::Any;
+use std::convert::TryInto;
 use tonic::{transport::NamedService, Code, Request, Response, Status};
 
-use super::pb::{HandlingService, RegisterHandlingEventRequest};
+use super::pb::{
+    to_datetime, BadRequest, FieldViolation, HandlingService, RegisterHandlingEventRequest,
+    RegisterHandlingEventResponse, RpcStatus,
+};
+
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
 
 #[derive(Debug, Default)]
 pub struct HandlingServiceImpl<S: Service>(S);
@@ -24,12 +30,11 @@ impl<S: Service + Sync + Send + 'static> HandlingService for HandlingServiceImpl
     async fn register_handling_event(
         &self,
         request: Request<RegisterHandlingEventRequest>,
-    ) -> Result<Response<()>, Status> {
+    ) -> Result<Response<RegisterHandlingEventResponse>, Status> {
         let message = request.into_inner();
         let completed = match message.completed {
             Some(prost_timestamp) => {
-                let sys_time = SystemTime::try_from(prost_timestamp).unwrap();
-                DateTime::<Utc>::from(sys_time)
+                to_datetime("completed", prost_timestamp).map_err(to_status)?
             }
             None => Utc::now(),
         };
@@ -37,10 +42,15 @@ impl<S: Service + Sync + Send + 'static> HandlingService for HandlingServiceImpl
         let event_type_result: Result<HandlingEventType, Error> = message.event_type.try_into();
         let event_type = match event_type_result {
             Ok(etype) => etype,
-            Err(err) => return Err(Status::new(Code::InvalidArgument, err.to_string())),
+            Err(_) => {
+                return Err(to_status(Error::InvalidField {
+                    field: "event_type",
+                    description: format!("unknown event type {}", message.event_type),
+                }))
+            }
         };
 
-        if let Err(error) = self
+        match self
             .0
             .register_handling_event(
                 completed,
@@ -51,12 +61,67 @@ impl<S: Service + Sync + Send + 'static> HandlingService for HandlingServiceImpl
             )
             .await
         {
-            return Err(Status::new(Code::Internal, error.to_string()));
+            Ok(e) => Ok(Response::new(RegisterHandlingEventResponse {
+                is_expected: e.is_expected,
+            })),
+            Err(error) => Err(to_status(error)),
+        }
+    }
+}
+
+/// Maps service errors to gRPC statuses. Errors caused by a particular request
+/// field carry a google.rpc.BadRequest detail naming that field.
+fn to_status(err: Error) -> Status {
+    let message = err.to_string();
+    match &err {
+        Error::UnknownCargo(_) => with_field_violation(Code::NotFound, message, "id"),
+        Error::UnknownVoyage(_) => with_field_violation(Code::NotFound, message, "voyage_number"),
+        Error::UnknownLocation(_) => with_field_violation(Code::NotFound, message, "un_locode"),
+        Error::InvalidField { field, .. } => {
+            with_field_violation(Code::InvalidArgument, message, field)
+        }
+        Error::NotFound(_) => Status::new(Code::NotFound, message),
+        Error::VersionConflict(_) => Status::new(Code::Aborted, message),
+        Error::ParsingError => Status::new(Code::InvalidArgument, message),
+        Error::IllegalTransition { .. } => Status::new(Code::FailedPrecondition, message),
+        Error::RepositoryError(_) | Error::PublishError(_) => {
+            Status::new(Code::Unavailable, message)
+        }
+        #[cfg(feature = "amqp")]
+        Error::LapinError(_) => Status::new(Code::Unavailable, message),
+        #[cfg(feature = "nats")]
+        Error::NatsError(_) => Status::new(Code::Unavailable, message),
+        Error::HandlingError | Error::EncodeError(_) | Error::DecodeError(_) => {
+            Status::new(Code::Internal, message)
         }
-        Ok(Response::new(()))
     }
 }
 
+fn with_field_violation(code: Code, message: String, field: &str) -> Status {
+    let bad_request = BadRequest {
+        field_violations: vec![FieldViolation {
+            field: field.to_string(),
+            description: message.clone(),
+        }],
+    };
+    let status = RpcStatus {
+        code: code as i32,
+        message: message.clone(),
+        details: vec![Any {
+            type_url: BAD_REQUEST_TYPE_URL.to_string(),
+            value: encode(&bad_request),
+        }],
+    };
+    Status::with_details(code, message, encode(&status).into())
+}
+
+fn encode<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    // A Vec grows as needed, so encoding into it never fails.
+    let _ = msg.encode(&mut buf);
+    buf
+}
+
 // For health checking
 // TODO: make HandlingService implementation useful for this case, remove nested type annotation.
 pub struct NamedHandlingServiceImpl;
@@ -0,0 +1,134 @@
+use crate::application::envelope::Envelope;
+use crate::application::integration_events::EventHandler;
+use crate::application::pb::OrderingKey;
+use crate::domain::handling::TrackingID;
+use crate::domain::inbox::CargoInbox;
+use crate::domain::{retry_on_conflict, Repository, Version, Versioned};
+use crate::Error;
+use async_trait::async_trait;
+use log::info;
+use prost::Message;
+use std::marker::PhantomData;
+
+/// InboxHandler makes a handler idempotent. It skips the messages recorded in
+/// the inbox of their cargo, and the events older than one already handled,
+/// then records the message once the handler succeeded.
+///
+/// Messages are recognized by their ID and ordered by their sequence number.
+/// Publishers may set neither: such a message is recognized by a digest of
+/// its content instead, and ordered by the sequence in its payload if it has
+/// one.
+///
+/// A message handled but not yet recorded when the service stops is handled
+/// again, which the handler still has to tolerate.
+pub struct InboxHandler<EH, R, E> {
+    handler: EH,
+    inboxes: R,
+    _event: PhantomData<fn(E)>,
+}
+
+impl<EH, R, E> InboxHandler<EH, R, E>
+where
+    EH: EventHandler<E>,
+    R: Repository<TrackingID, CargoInbox>,
+    E: Message + OrderingKey + Send + 'static,
+{
+    pub fn new(inboxes: R, handler: EH) -> Self {
+        InboxHandler {
+            handler,
+            inboxes,
+            _event: PhantomData,
+        }
+    }
+
+    // Returns the inbox of the cargo with its version, which is None while
+    // nothing was recorded for the cargo.
+    async fn find(&self, tracking_id: &str) -> Result<(CargoInbox, Option<Version>), Error> {
+        match self.inboxes.find_versioned(tracking_id.to_string()).await {
+            Ok(Versioned { value, version }) => Ok((value, Some(version))),
+            Err(Error::NotFound(_)) => Ok((CargoInbox::default(), None)),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn record(
+        &self,
+        tracking_id: &str,
+        envelope: &Envelope,
+        sequence: Option<u64>,
+        digest: Option<u64>,
+    ) -> Result<(), Error> {
+        retry_on_conflict(|| async move {
+            let (mut inbox, version) = self.find(tracking_id).await?;
+            inbox.record(envelope.id.as_deref(), &envelope.r#type, sequence, digest);
+            self.inboxes
+                .store_if_version(tracking_id.to_string(), &inbox, version)
+                .await
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<EH, R, E> EventHandler<E> for InboxHandler<EH, R, E>
+where
+    EH: EventHandler<E>,
+    R: Repository<TrackingID, CargoInbox>,
+    E: Message + OrderingKey + Send + 'static,
+{
+    // Without an envelope there is nothing to recognize the message by.
+    async fn handle(&self, e: E) -> Result<(), Error> {
+        self.handler.handle(e).await
+    }
+
+    async fn handle_message(&self, e: E, envelope: Envelope) -> Result<(), Error> {
+        let tracking_id = e.ordering_key().to_string();
+        let sequence = envelope.sequence.or_else(|| e.sequence());
+        let digest = match envelope.id {
+            Some(_) => None,
+            None => {
+                let mut payload = vec![];
+                e.encode(&mut payload)?;
+                Some(digest(&[
+                    envelope.r#type.as_bytes(),
+                    tracking_id.as_bytes(),
+                    &payload,
+                ]))
+            }
+        };
+        let (inbox, _) = self.find(&tracking_id).await?;
+        if inbox.is_duplicate(envelope.id.as_deref())
+            || inbox.is_redelivered(&envelope.r#type, digest)
+        {
+            info!(
+                "Skipping {} message {:?} of cargo {}, handled already",
+                envelope.r#type, envelope.id, tracking_id
+            );
+            return Ok(());
+        }
+        if inbox.is_stale(&envelope.r#type, sequence) {
+            info!(
+                "Skipping {} message {:?} of cargo {}, a later event is handled",
+                envelope.r#type, envelope.id, tracking_id
+            );
+            return Ok(());
+        }
+        self.handler.handle_message(e, envelope.clone()).await?;
+        self.record(&tracking_id, &envelope, sequence, digest).await
+    }
+}
+
+// FNV-1a of the length prefixed parts. Unlike the hasher of the standard
+// library it is the same in every build, as digests are stored.
+fn digest(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        let len = (part.len() as u64).to_le_bytes();
+        for byte in len.iter().chain(part.iter()) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
@@ -1,18 +1,79 @@
-use crate::application::pb::{CargoDestinationChanged, NewCargoBooked};
-use crate::domain::handling::{Cargo, HandlingEvent, TrackingID};
-use crate::domain::Repository;
+use crate::application::envelope::Envelope;
+use crate::application::pb::{
+    CargoDestinationChanged, CargoToRouteAssigned, NewCargoBooked, OrderingKey, TypeName,
+};
+use crate::domain::handling::{unknown, Cargo, HandlingEvent, TrackingID};
+use crate::domain::itinerary::Itinerary;
+use crate::domain::{retry_on_conflict, Repository, Versioned};
 use crate::Error;
 use async_trait::async_trait;
 use log::info;
+use prost::Message;
 use std::convert::TryInto;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Arc;
 
 #[async_trait]
 pub trait EventService: Send + Sync {
-    async fn cargo_was_handled(&self, e: HandlingEvent) -> Result<(), Error>;
+    /// Publishes the event as the message `id`, which is the same on every
+    /// attempt to publish it.
+    async fn cargo_was_handled(&self, e: HandlingEvent, id: String) -> Result<(), Error>;
 }
 
-pub trait EventHandler<Event>: Clone + Send {
-    fn handle(&self, e: Event) -> Result<(), Error>;
+/// EventHandler handles the integration events of one type. The event bus
+/// calls it concurrently for events of different cargos, so the state it
+/// shares between events is kept in services that are safe to share, such as
+/// repositories.
+#[async_trait]
+pub trait EventHandler<Event>: Send + Sync
+where
+    Event: Send + 'static,
+{
+    async fn handle(&self, e: Event) -> Result<(), Error>;
+
+    /// Handles an event together with the envelope it was delivered in.
+    /// Handlers that need the metadata of the message implement this one.
+    async fn handle_message(&self, e: Event, _envelope: Envelope) -> Result<(), Error> {
+        self.handle(e).await
+    }
+}
+
+#[async_trait]
+impl<Event, EH> EventHandler<Event> for Arc<EH>
+where
+    Event: Send + 'static,
+    EH: EventHandler<Event> + ?Sized,
+{
+    async fn handle(&self, e: Event) -> Result<(), Error> {
+        self.deref().handle(e).await
+    }
+
+    async fn handle_message(&self, e: Event, envelope: Envelope) -> Result<(), Error> {
+        self.deref().handle_message(e, envelope).await
+    }
+}
+
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// OrderedSpawner runs a task keyed by the tracking ID of a cargo in order
+/// with the integration events of the cargo: after those received before it
+/// are handled, and before those received after it.
+pub type OrderedSpawner = Arc<dyn Fn(String, Task) + Send + Sync>;
+
+/// SubscribeManager delivers the integration events of type `E`, named by
+/// their `TypeName`, to the handler.
+#[async_trait]
+pub trait SubscribeManager {
+    async fn subscribe<E, EH>(&mut self, eh: EH) -> Result<(), Box<dyn std::error::Error>>
+    where
+        E: Message + TypeName + OrderingKey + Default + 'static,
+        EH: EventHandler<E> + 'static;
+
+    /// Returns the spawner of the tasks that have to be ordered with the
+    /// delivered events.
+    fn ordered_spawner(&self) -> OrderedSpawner;
 }
 
 #[derive(Clone)]
@@ -32,15 +93,26 @@ where
     }
 }
 
+#[async_trait]
 impl<T> EventHandler<NewCargoBooked> for NewCargoBookedEventHandler<T>
 where
     T: Repository<TrackingID, Cargo>,
 {
-    fn handle(&self, e: NewCargoBooked) -> Result<(), Error> {
+    async fn handle(&self, e: NewCargoBooked) -> Result<(), Error> {
         let cargo: Cargo = e.try_into()?;
         info!("New cargo booked {}", cargo.tracking_id);
-        self.cargos.store(cargo.tracking_id.clone(), &cargo)?;
-        Ok(())
+        // A redelivered booking must not undo later changes of the cargo.
+        match self
+            .cargos
+            .store_if_version(cargo.tracking_id.clone(), &cargo, None)
+            .await
+        {
+            Err(Error::VersionConflict(id)) => {
+                info!("Cargo {} is already booked", id);
+                Ok(())
+            }
+            res => res.map(|_| ()),
+        }
     }
 }
 
@@ -61,18 +133,81 @@ where
     }
 }
 
+#[async_trait]
 impl<T> EventHandler<CargoDestinationChanged> for CargoDestinationChangedEventHandler<T>
 where
     T: Repository<TrackingID, Cargo>,
 {
-    fn handle(&self, e: CargoDestinationChanged) -> Result<(), Error> {
+    async fn handle(&self, e: CargoDestinationChanged) -> Result<(), Error> {
         info!(
             "Cargo {} destination changed {}",
             e.tracking_id, e.destination
         );
-        let mut cargo = self.cargos.find(e.tracking_id)?;
-        cargo.destination = e.destination;
-        self.cargos.store(cargo.tracking_id.clone(), &cargo)?;
-        Ok(())
+        let id = e.tracking_id;
+        let destination = e.destination;
+        update_cargo(&self.cargos, &id, |cargo| {
+            cargo.destination = destination.clone()
+        })
+        .await
     }
 }
+
+#[derive(Clone)]
+pub struct CargoToRouteAssignedEventHandler<T>
+where
+    T: Repository<TrackingID, Cargo>,
+{
+    cargos: T,
+}
+
+impl<T> CargoToRouteAssignedEventHandler<T>
+where
+    T: Repository<TrackingID, Cargo>,
+{
+    pub fn new(cargos: T) -> Self {
+        CargoToRouteAssignedEventHandler { cargos }
+    }
+}
+
+#[async_trait]
+impl<T> EventHandler<CargoToRouteAssigned> for CargoToRouteAssignedEventHandler<T>
+where
+    T: Repository<TrackingID, Cargo>,
+{
+    async fn handle(&self, e: CargoToRouteAssigned) -> Result<(), Error> {
+        info!("Cargo {} assigned to route", e.tracking_id);
+        let id = e.tracking_id;
+        let itinerary: Itinerary = e.itinerary.unwrap_or_default().try_into()?;
+        update_cargo(&self.cargos, &id, |cargo| {
+            cargo.itinerary = itinerary.clone()
+        })
+        .await
+    }
+}
+
+// Applies the change to the stored cargo unless the cargo changes in the
+// meantime, in which case the change is applied to the new one.
+async fn update_cargo<T, F>(cargos: &T, id: &str, change: F) -> Result<(), Error>
+where
+    T: Repository<TrackingID, Cargo>,
+    F: Fn(&mut Cargo),
+{
+    retry_on_conflict(|| {
+        let change = &change;
+        async move {
+            let Versioned {
+                value: mut cargo,
+                version,
+            } = cargos
+                .find_versioned(id.to_string())
+                .await
+                .map_err(|err| unknown(err, Error::UnknownCargo(id.to_string())))?;
+            change(&mut cargo);
+            cargos
+                .store_if_version(id.to_string(), &cargo, Some(version))
+                .await
+        }
+    })
+    .await?;
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use super::service::Service;
-use crate::domain::handling::{HandlingEventType, TrackingID};
+use crate::domain::handling::{HandlingEvent, HandlingEventType, TrackingID};
 use crate::domain::{location::UNLocode, voyage::VoyageNumber};
 use crate::Error;
 use async_trait::async_trait;
@@ -11,7 +11,7 @@ pub struct LoggingService {
 }
 
 impl LoggingService {
-    pub fn new(next: Box<dyn Service + Send + Sync>) -> impl Service {
+    pub fn new(next: Box<dyn Service + Send + Sync>) -> Self {
         Self { next }
     }
 }
@@ -25,19 +25,20 @@ impl Service for LoggingService {
         voyage_number: VoyageNumber,
         un_locode: UNLocode,
         event_type: HandlingEventType,
-    ) -> Result<(), Error> {
+    ) -> Result<HandlingEvent, Error> {
         let begin = Utc::now();
         let res = self
             .next
             .register_handling_event(completed, id.clone(), voyage_number, un_locode, event_type)
             .await;
-        let err = match &res {
-            Ok(_) => "".to_string(),
-            Err(err) => format!("{:?}", err),
+        let (expected, err) = match &res {
+            Ok(e) => (e.is_expected, "".to_string()),
+            Err(err) => (false, format!("{:?}", err)),
         };
         info!(
-            "method: register_handling_event, id: {}, err: {}, took: {}",
+            "method: register_handling_event, id: {}, expected: {}, err: {}, took: {}",
             id,
+            expected,
             err,
             Utc::now().signed_duration_since(begin)
         );
@@ -1,5 +1,8 @@
+pub mod envelope;
 pub mod grpc_server;
+pub mod inbox;
 pub mod integration_events;
 pub mod logging_service;
+pub mod parking;
 pub mod pb;
 pub mod service;
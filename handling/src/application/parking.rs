@@ -0,0 +1,325 @@
+use crate::application::envelope::Envelope;
+use crate::application::integration_events::{EventHandler, OrderedSpawner};
+use crate::application::pb::{OrderingKey, TypeName};
+use crate::domain::handling::TrackingID;
+use crate::domain::parking::{DeadLetter, ParkedEvent, ParkingLot};
+use crate::domain::{retry_on_conflict, PageRequest, Repository, Version, Versioned};
+use crate::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::prelude::*;
+use log::{error, info, warn};
+use prost::Message;
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+// How often parked events are tried again and expired.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SWEEP_PAGE_SIZE: usize = 100;
+
+type Handling = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+// Decodes a parked event and hands it to the handler it was parked by.
+type Replay = Arc<dyn Fn(Vec<u8>, Envelope) -> Handling + Send + Sync>;
+
+/// Parking holds back the integration events of cargos the service does not
+/// know yet, because their booking has not arrived. A parked event is
+/// replayed once the cargo is booked, and moved to the dead letters of its
+/// parking lot when its cargo is still unknown after the expiry, or when it
+/// fails once replayed.
+///
+/// The events of a cargo are parked and replayed in order with its other
+/// events, so each is replayed once and in the order it arrived.
+#[derive(Clone)]
+pub struct Parking<R> {
+    lots: R,
+    expiry: Duration,
+    replays: Arc<RwLock<HashMap<String, Replay>>>,
+}
+
+impl<R> Parking<R>
+where
+    R: Repository<TrackingID, ParkingLot> + 'static,
+{
+    pub fn new(lots: R, expiry: Duration) -> Self {
+        Parking {
+            lots,
+            expiry,
+            replays: Arc::default(),
+        }
+    }
+
+    /// Returns a handler that parks the events `handler` fails to handle
+    /// because their cargo is unknown. Parked events are replayed to
+    /// `handler`.
+    pub fn parking<E, EH>(&self, handler: EH) -> ParkingHandler<EH, R, E>
+    where
+        E: Message + TypeName + OrderingKey + Clone + Default + 'static,
+        EH: EventHandler<E> + 'static,
+    {
+        let handler = Arc::new(handler);
+        let replay_handler = handler.clone();
+        let replay: Replay = Arc::new(move |payload: Vec<u8>, envelope: Envelope| {
+            let handler = replay_handler.clone();
+            Box::pin(async move {
+                let e = E::decode(Bytes::from(payload))?;
+                handler.handle_message(e, envelope).await
+            })
+        });
+        if let Ok(mut replays) = self.replays.write() {
+            replays.insert(E::name().to_string(), replay);
+        }
+        ParkingHandler {
+            handler,
+            parking: self.clone(),
+            _event: PhantomData,
+        }
+    }
+
+    /// Returns a handler that replays the parked events of a cargo once
+    /// `handler` has handled its booking.
+    pub fn replaying<E, EH>(&self, handler: EH) -> ReplayingHandler<EH, R, E>
+    where
+        E: OrderingKey + Send + 'static,
+        EH: EventHandler<E>,
+    {
+        ReplayingHandler {
+            handler,
+            parking: self.clone(),
+            _event: PhantomData,
+        }
+    }
+
+    /// Tries the parked events of all cargos again in the background, and
+    /// expires them, until the runtime shuts down. This replays the events
+    /// whose booking arrived while they were being parked.
+    pub fn spawn(self, spawner: OrderedSpawner) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.replay_all(&spawner).await {
+                    error!("Failed to replay parked events: {}", err);
+                }
+            }
+        })
+    }
+
+    /// Tries the parked events of all cargos again, each cargo in order with
+    /// its events through the spawner of the event bus. Fails only if the
+    /// parking lots can not be listed.
+    pub async fn replay_all(&self, spawner: &OrderedSpawner) -> Result<(), Error> {
+        let mut request = Some(PageRequest::first(SWEEP_PAGE_SIZE));
+        while let Some(page_request) = request {
+            let page = self.lots.find_page(page_request.clone()).await?;
+            for lot in page.items.iter().filter(|lot| !lot.parked.is_empty()) {
+                let tracking_id = lot.tracking_id.clone();
+                let parking = self.clone();
+                let (done_tx, done_rx) = oneshot::channel();
+                spawner(
+                    tracking_id.clone(),
+                    Box::pin(async move {
+                        let _ = done_tx.send(parking.replay(&tracking_id).await);
+                    }),
+                );
+                // Fails if the replay panicked.
+                let res = done_rx.await.unwrap_or(Err(Error::HandlingError));
+                if let Err(err) = res {
+                    error!(
+                        "Failed to replay parked events of cargo {}: {}",
+                        lot.tracking_id, err
+                    );
+                }
+            }
+            request = page_request.next(&page);
+        }
+        Ok(())
+    }
+
+    // Hands the parked events of the cargo to their handlers. Those still
+    // waiting for their cargo stay parked until they expire. Only runs in
+    // order with the events of the cargo, which keeps replays of the same
+    // events from overlapping.
+    async fn replay(&self, tracking_id: &str) -> Result<(), Error> {
+        let (lot, _) = self.find(tracking_id).await?;
+        if lot.parked.is_empty() {
+            return Ok(());
+        }
+        let now = Utc::now();
+        let mut done = Vec::new();
+        let mut dead_letters = Vec::new();
+        for event in lot.parked {
+            let res = match self.replay_func(&event.kind) {
+                Some(replay) => replay(event.payload.clone(), envelope(&event)).await,
+                None => Err(Error::HandlingError),
+            };
+            match res {
+                Ok(()) => {
+                    info!("Replayed {} of cargo {}", event.kind, tracking_id);
+                    done.push(event);
+                }
+                Err(Error::UnknownCargo(_)) if !self.expired(&event, now) => {}
+                Err(err) => {
+                    warn!(
+                        "Dead-lettering parked {} of cargo {}: {}",
+                        event.kind, tracking_id, err
+                    );
+                    done.push(event.clone());
+                    dead_letters.push(DeadLetter {
+                        event,
+                        reason: err.to_string(),
+                        dead_lettered_at: now,
+                    });
+                }
+            }
+        }
+        if done.is_empty() {
+            return Ok(());
+        }
+        self.update(tracking_id, |lot| {
+            lot.parked.retain(|event| !done.contains(event));
+            lot.dead_letters.extend(dead_letters.iter().cloned());
+        })
+        .await
+    }
+
+    async fn park<E: Message + TypeName>(
+        &self,
+        tracking_id: &str,
+        e: &E,
+        envelope: &Envelope,
+    ) -> Result<(), Error> {
+        let mut payload = vec![];
+        e.encode(&mut payload)?;
+        let event = ParkedEvent {
+            kind: E::name().to_string(),
+            payload,
+            attributes: envelope
+                .attributes()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            parked_at: Utc::now(),
+        };
+        info!("Parking {} of unknown cargo {}", event.kind, tracking_id);
+        self.update(tracking_id, |lot| lot.parked.push(event.clone()))
+            .await
+    }
+
+    fn replay_func(&self, kind: &str) -> Option<Replay> {
+        self.replays
+            .read()
+            .ok()
+            .and_then(|replays| replays.get(kind).cloned())
+    }
+
+    fn expired(&self, event: &ParkedEvent, now: DateTime<Utc>) -> bool {
+        let parked_for = now.signed_duration_since(event.parked_at);
+        parked_for
+            .to_std()
+            .is_ok_and(|parked_for| parked_for >= self.expiry)
+    }
+
+    // Returns the parking lot of the cargo with its version, which is None
+    // while no event of the cargo was parked.
+    async fn find(&self, tracking_id: &str) -> Result<(ParkingLot, Option<Version>), Error> {
+        match self.lots.find_versioned(tracking_id.to_string()).await {
+            Ok(Versioned { value, version }) => Ok((value, Some(version))),
+            Err(Error::NotFound(_)) => Ok((ParkingLot::new(tracking_id.to_string()), None)),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn update<F>(&self, tracking_id: &str, change: F) -> Result<(), Error>
+    where
+        F: Fn(&mut ParkingLot),
+    {
+        retry_on_conflict(|| {
+            let change = &change;
+            async move {
+                let (mut lot, version) = self.find(tracking_id).await?;
+                change(&mut lot);
+                self.lots
+                    .store_if_version(tracking_id.to_string(), &lot, version)
+                    .await
+            }
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+fn envelope(event: &ParkedEvent) -> Envelope {
+    Envelope::from_attributes(&event.kind, |name| {
+        event
+            .attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.clone())
+    })
+}
+
+/// ParkingHandler parks the events its handler fails to handle because
+/// their cargo is unknown, see `Parking::parking`.
+pub struct ParkingHandler<EH, R, E> {
+    handler: Arc<EH>,
+    parking: Parking<R>,
+    _event: PhantomData<fn(E)>,
+}
+
+#[async_trait]
+impl<EH, R, E> EventHandler<E> for ParkingHandler<EH, R, E>
+where
+    EH: EventHandler<E>,
+    R: Repository<TrackingID, ParkingLot> + 'static,
+    E: Message + TypeName + OrderingKey + Clone + Default + 'static,
+{
+    async fn handle(&self, e: E) -> Result<(), Error> {
+        self.handle_message(e, Envelope::of_type(E::name())).await
+    }
+
+    async fn handle_message(&self, e: E, envelope: Envelope) -> Result<(), Error> {
+        match self
+            .handler
+            .handle_message(e.clone(), envelope.clone())
+            .await
+        {
+            Err(Error::UnknownCargo(_)) => self.parking.park(e.ordering_key(), &e, &envelope).await,
+            res => res,
+        }
+    }
+}
+
+/// ReplayingHandler replays the parked events of a cargo once its handler
+/// has handled the booking, see `Parking::replaying`.
+pub struct ReplayingHandler<EH, R, E> {
+    handler: EH,
+    parking: Parking<R>,
+    _event: PhantomData<fn(E)>,
+}
+
+#[async_trait]
+impl<EH, R, E> EventHandler<E> for ReplayingHandler<EH, R, E>
+where
+    EH: EventHandler<E>,
+    R: Repository<TrackingID, ParkingLot> + 'static,
+    E: OrderingKey + Send + 'static,
+{
+    async fn handle(&self, e: E) -> Result<(), Error> {
+        let tracking_id = e.ordering_key().to_string();
+        self.handler.handle(e).await?;
+        self.parking.replay(&tracking_id).await
+    }
+
+    async fn handle_message(&self, e: E, envelope: Envelope) -> Result<(), Error> {
+        let tracking_id = e.ordering_key().to_string();
+        self.handler.handle_message(e, envelope).await?;
+        self.parking.replay(&tracking_id).await
+    }
+}
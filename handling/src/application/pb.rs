@@ -2,16 +2,23 @@ use crate::domain::handling::Cargo;
 use crate::domain::handling::HandlingActivity as DomainHandlingActivity;
 use crate::domain::handling::HandlingEvent as DomainHandlingEvent;
 use crate::domain::handling::HandlingEventType as DomainHandlingEventType;
+use crate::domain::itinerary::{Itinerary as DomainItinerary, Leg as DomainLeg};
 use crate::Error;
 use chrono::prelude::*;
-pub use pb::booking::{CargoDestinationChanged, NewCargoBooked};
+pub use pb::booking::{CargoDestinationChanged, CargoToRouteAssigned, NewCargoBooked};
+pub use pb::google::rpc::{bad_request::FieldViolation, BadRequest, Status as RpcStatus};
 pub use pb::handling::handling_service_client::HandlingServiceClient;
 pub use pb::handling::handling_service_server::{HandlingService, HandlingServiceServer};
-pub use pb::handling::{Activity, HandlingEvent, HandlingEventType, RegisterHandlingEventRequest};
+pub use pb::handling::{
+    Activity, HandlingEvent, HandlingEventType, RegisterHandlingEventRequest,
+    RegisterHandlingEventResponse,
+};
+use pb::itinerary::{Itinerary, Leg};
+use prost_types::Timestamp;
 use std::convert::{From, Into, TryFrom};
 use std::str::FromStr;
-use std::time::SystemTime;
 
+#[allow(dead_code, clippy::module_inception)]
 mod pb {
     pub mod handling {
         tonic::include_proto!("handling"); // The string specified here must match the proto package name
@@ -22,6 +29,11 @@ mod pb {
     pub mod itinerary {
         tonic::include_proto!("itinerary");
     }
+    pub mod google {
+        pub mod rpc {
+            tonic::include_proto!("google.rpc");
+        }
+    }
 }
 
 impl FromStr for HandlingEventType {
@@ -85,21 +97,46 @@ impl TryFrom<NewCargoBooked> for Cargo {
     type Error = Error;
     fn try_from(value: NewCargoBooked) -> Result<Self, Self::Error> {
         let arrival_deadline = match value.arrival_deadline {
-            Some(prost_timestamp) => {
-                let sys_time = SystemTime::try_from(prost_timestamp).unwrap();
-                DateTime::<Utc>::from(sys_time)
-            }
+            Some(prost_timestamp) => to_datetime("arrival_deadline", prost_timestamp)?,
             None => Utc::now(), // TODO
         };
         Ok(Cargo {
             tracking_id: value.tracking_id,
             origin: value.origin,
             destination: value.destination,
-            arrival_deadline: arrival_deadline,
+            arrival_deadline,
+            itinerary: DomainItinerary::default(),
         })
     }
 }
 
+impl TryFrom<Leg> for DomainLeg {
+    type Error = Error;
+    fn try_from(value: Leg) -> Result<Self, Self::Error> {
+        let load_time = value.load_time.ok_or(Error::ParsingError)?;
+        let unload_time = value.unload_time.ok_or(Error::ParsingError)?;
+        Ok(DomainLeg {
+            voyage_number: value.voyage_number,
+            load_location: value.load_location,
+            unload_location: value.unload_location,
+            load_time: to_datetime("load_time", load_time)?,
+            unload_time: to_datetime("unload_time", unload_time)?,
+        })
+    }
+}
+
+impl TryFrom<Itinerary> for DomainItinerary {
+    type Error = Error;
+    fn try_from(value: Itinerary) -> Result<Self, Self::Error> {
+        let legs = value
+            .legs
+            .into_iter()
+            .map(DomainLeg::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(DomainItinerary { legs })
+    }
+}
+
 impl From<DomainHandlingActivity> for Activity {
     fn from(value: DomainHandlingActivity) -> Self {
         Activity {
@@ -115,10 +152,47 @@ impl From<DomainHandlingEvent> for HandlingEvent {
         HandlingEvent {
             tracking_id: value.tracking_id,
             activity: Some(value.activity.into()),
+            registration_time: Some(to_timestamp(value.registration_time)),
+            completion_time: Some(to_timestamp(value.completion_time)),
+            is_expected: value.is_expected,
         }
     }
 }
 
+// The range of google.protobuf.Timestamp: 0001-01-01T00:00:00Z to
+// 9999-12-31T23:59:59.999999999Z.
+const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+
+/// Converts a protobuf timestamp, rejecting values outside of the range
+/// allowed by google.protobuf.Timestamp instead of overflowing.
+pub fn to_datetime(field: &'static str, value: Timestamp) -> Result<DateTime<Utc>, Error> {
+    let invalid = || Error::InvalidField {
+        field,
+        description: format!(
+            "timestamp {}s {}ns is out of range",
+            value.seconds, value.nanos
+        ),
+    };
+    if value.seconds < MIN_TIMESTAMP_SECONDS
+        || value.seconds > MAX_TIMESTAMP_SECONDS
+        || value.nanos < 0
+        || value.nanos > 999_999_999
+    {
+        return Err(invalid());
+    }
+    Utc.timestamp_opt(value.seconds, value.nanos as u32)
+        .single()
+        .ok_or_else(invalid)
+}
+
+fn to_timestamp(value: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: value.timestamp(),
+        nanos: value.timestamp_subsec_nanos() as i32,
+    }
+}
+
 pub trait TypeName {
     fn name() -> &'static str;
 }
@@ -135,8 +209,54 @@ impl TypeName for CargoDestinationChanged {
     }
 }
 
+impl TypeName for CargoToRouteAssigned {
+    fn name() -> &'static str {
+        "CargoToRouteAssigned"
+    }
+}
+
 impl TypeName for HandlingEvent {
     fn name() -> &'static str {
         "HandlingEvent"
     }
 }
+
+/// OrderingKey names the cargo an integration event is about. Events of the
+/// same cargo are handled in the order they are received.
+pub trait OrderingKey {
+    fn ordering_key(&self) -> &str;
+
+    /// The position of the event among the events of its type about the
+    /// cargo, for events that carry one in their payload.
+    fn sequence(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl OrderingKey for NewCargoBooked {
+    fn ordering_key(&self) -> &str {
+        &self.tracking_id
+    }
+}
+
+impl OrderingKey for CargoDestinationChanged {
+    fn ordering_key(&self) -> &str {
+        &self.tracking_id
+    }
+
+    fn sequence(&self) -> Option<u64> {
+        Some(self.version).filter(|version| *version > 0)
+    }
+}
+
+impl OrderingKey for CargoToRouteAssigned {
+    fn ordering_key(&self) -> &str {
+        &self.tracking_id
+    }
+}
+
+impl OrderingKey for HandlingEvent {
+    fn ordering_key(&self) -> &str {
+        &self.tracking_id
+    }
+}
@@ -1,6 +1,7 @@
-use super::integration_events::EventService;
-use crate::domain::handling::{HandlingEvent, HandlingEventFactory, HandlingEventType, TrackingID};
-use crate::domain::{location::UNLocode, voyage::VoyageNumber, Repository};
+use crate::domain::handling::{
+    HandlingEvent, HandlingEventFactory, HandlingEventRepository, HandlingEventType, TrackingID,
+};
+use crate::domain::{location::UNLocode, retry_on_conflict, voyage::VoyageNumber};
 use crate::Error;
 use async_trait::async_trait;
 use chrono::prelude::*;
@@ -14,40 +15,34 @@ pub trait Service {
         voyage_number: VoyageNumber,
         un_locode: UNLocode,
         event_type: HandlingEventType,
-    ) -> Result<(), Error>;
+    ) -> Result<HandlingEvent, Error>;
 }
 
-pub struct ServiceImpl<R, F, H> {
+/// ServiceImpl registers handling events. Registered events are published
+/// through the outbox of the handling event repository, not by the service.
+pub struct ServiceImpl<R, F> {
     handling_event_repository: R,
     handling_event_factory: F,
-    event_handler: H,
 }
 
-impl<R, F, H> ServiceImpl<R, F, H>
+impl<R, F> ServiceImpl<R, F>
 where
-    R: Repository<TrackingID, HandlingEvent>,
+    R: HandlingEventRepository,
     F: HandlingEventFactory,
-    H: EventService,
 {
-    pub fn new_service(
-        handling_event_repository: R,
-        handling_event_factory: F,
-        event_handler: H,
-    ) -> Self {
+    pub fn new_service(handling_event_repository: R, handling_event_factory: F) -> Self {
         ServiceImpl {
             handling_event_repository,
             handling_event_factory,
-            event_handler,
         }
     }
 }
 
 #[async_trait]
-impl<R, F, H> Service for ServiceImpl<R, F, H>
+impl<R, F> Service for ServiceImpl<R, F>
 where
-    R: Repository<TrackingID, HandlingEvent>,
+    R: HandlingEventRepository,
     F: HandlingEventFactory,
-    H: EventService,
 {
     async fn register_handling_event(
         &self,
@@ -56,27 +51,34 @@ where
         voyage_number: VoyageNumber,
         un_locode: UNLocode,
         event_type: HandlingEventType,
-    ) -> Result<(), Error> {
-        match event_type {
-            HandlingEventType::NotHandled
-                if id.is_empty() || voyage_number.is_empty() || un_locode.is_empty() =>
-            {
-                return Err(Error::InvalidArgument)
-            }
-            _ => (),
-        }
+    ) -> Result<HandlingEvent, Error> {
+        let e = self
+            .handling_event_factory
+            .create_handling_event(
+                Utc::now(),
+                completed,
+                id,
+                voyage_number,
+                un_locode,
+                event_type,
+            )
+            .await?;
 
-        let e = self.handling_event_factory.create_handling_event(
-            Utc::now(),
-            completed,
-            id.clone(),
-            voyage_number,
-            un_locode,
-            event_type,
-        )?;
-
-        self.handling_event_repository.store(id, &e)?;
-        self.event_handler.cargo_was_handled(e).await?;
-        Ok(())
+        // Another event of the cargo may be registered concurrently, the
+        // history must not change between validating and appending.
+        retry_on_conflict(|| {
+            let repository = &self.handling_event_repository;
+            let e = &e;
+            async move {
+                let mut history = repository
+                    .query_handling_history(e.tracking_id.clone())
+                    .await?;
+                let version = history.version();
+                history.append(e.clone())?;
+                repository.append_if_version(e, version).await
+            }
+        })
+        .await?;
+        Ok(e)
     }
 }
@@ -20,7 +20,8 @@ struct Opt {
     /// date in format dd.mm.yyyy
     completed: Option<String>,
 
-    #[structopt(long, short)]
+    #[structopt(long, short, default_value = "")]
+    /// required for Load and Unload events only
     voyage_number: String,
 
     #[structopt(long, short)]
@@ -36,8 +37,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let completed = match opt.completed {
         Some(date) => {
             let naive_date = NaiveDate::parse_from_str(&date, "%d.%m.%Y")?;
-            let naive_datetime: NaiveDateTime = naive_date.and_hms(0, 0, 0);
-            let datetime_utc = DateTime::<Utc>::from_utc(naive_datetime, Utc);
+            let naive_datetime: NaiveDateTime = naive_date.and_time(NaiveTime::MIN);
+            let datetime_utc = Utc.from_utc_datetime(&naive_datetime);
             let ts: SystemTime = datetime_utc.into();
             Some(Timestamp::from(ts))
         }
@@ -45,7 +46,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     let req = RegisterHandlingEventRequest {
-        completed: completed,
+        completed,
         id: opt.id,
         voyage_number: opt.voyage_number,
         un_locode: opt.location,
@@ -1,11 +1,14 @@
 #![allow(dead_code)]
+use super::itinerary::Itinerary;
 use super::location::{Location, UNLocode};
 use super::voyage::{Voyage, VoyageNumber};
-use super::Repository;
+use super::{Page, PageRequest, Repository, Version};
 use crate::Error;
+use async_trait::async_trait;
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HandlingEventType {
     NotHandled,
     Load,
@@ -15,21 +18,250 @@ pub enum HandlingEventType {
     Customs,
 }
 
-#[derive(Debug, Clone)]
+/// TransportStatus is the lifecycle state of a cargo as seen by handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TransportStatus {
+    #[default]
+    NotReceived,
+    InPort,
+    OnboardCarrier,
+    Claimed,
+}
+
+impl TransportStatus {
+    /// Returns the status of a cargo after it has been handled. A cargo is
+    /// received once, loaded and unloaded in turns, cleared through customs
+    /// while in port, and claimed at the end of its journey.
+    pub fn next(self, event_type: &HandlingEventType) -> Option<TransportStatus> {
+        match (self, event_type) {
+            (TransportStatus::NotReceived, HandlingEventType::Receive) => {
+                Some(TransportStatus::InPort)
+            }
+            (TransportStatus::InPort, HandlingEventType::Customs) => Some(TransportStatus::InPort),
+            (TransportStatus::InPort, HandlingEventType::Load) => {
+                Some(TransportStatus::OnboardCarrier)
+            }
+            (TransportStatus::OnboardCarrier, HandlingEventType::Unload) => {
+                Some(TransportStatus::InPort)
+            }
+            (TransportStatus::InPort, HandlingEventType::Claim) => Some(TransportStatus::Claimed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HandlingActivity {
     pub r#type: HandlingEventType,
     pub location: UNLocode,
     pub voyage_number: VoyageNumber,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HandlingEvent {
     pub tracking_id: TrackingID,
     pub activity: HandlingActivity,
+    pub registration_time: DateTime<Utc>,
+    pub completion_time: DateTime<Utc>,
+    /// Whether the event was expected by the cargo itinerary at the moment of
+    /// registration.
+    pub is_expected: bool,
+}
+
+/// HandlingHistory is the handling history of a cargo. It keeps every
+/// registered handling event ordered by completion time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandlingHistory {
+    pub tracking_id: TrackingID,
+    handling_events: Vec<HandlingEvent>,
+    transport_status: TransportStatus,
+}
+
+impl HandlingHistory {
+    pub fn new(tracking_id: TrackingID) -> Self {
+        HandlingHistory {
+            tracking_id,
+            handling_events: Vec::new(),
+            transport_status: TransportStatus::default(),
+        }
+    }
+
+    /// Adds an event to the history. Events completed at the same time keep
+    /// their registration order. The event is rejected if the resulting
+    /// sequence of events is not a valid cargo lifecycle.
+    pub fn append(&mut self, e: HandlingEvent) -> Result<(), Error> {
+        let pos = self
+            .handling_events
+            .partition_point(|h| h.completion_time <= e.completion_time);
+        self.handling_events.insert(pos, e);
+        match Self::replay(&self.handling_events) {
+            Ok(status) => {
+                self.transport_status = status;
+                Ok(())
+            }
+            Err(err) => {
+                self.handling_events.remove(pos);
+                Err(err)
+            }
+        }
+    }
+
+    fn replay(events: &[HandlingEvent]) -> Result<TransportStatus, Error> {
+        events
+            .iter()
+            .try_fold(TransportStatus::default(), |status, e| {
+                status
+                    .next(&e.activity.r#type)
+                    .ok_or_else(|| Error::IllegalTransition {
+                        tracking_id: e.tracking_id.clone(),
+                        status,
+                        event_type: e.activity.r#type.clone(),
+                    })
+            })
+    }
+
+    pub fn transport_status(&self) -> TransportStatus {
+        self.transport_status
+    }
+
+    pub fn handling_events(&self) -> &[HandlingEvent] {
+        &self.handling_events
+    }
+
+    /// Events are never removed from a history, so their number versions it.
+    pub fn version(&self) -> Version {
+        self.handling_events.len() as Version
+    }
+
+    pub fn most_recently_completed_event(&self) -> Option<&HandlingEvent> {
+        self.handling_events.last()
+    }
+}
+
+/// HandlingEventRepository stores handling events. Unlike `Repository`, it
+/// never replaces previously stored events of a cargo.
+#[async_trait]
+pub trait HandlingEventRepository: Clone + Send + Sync {
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error>;
+    /// Appends the event only if the history of the cargo still has the
+    /// `expected` version, fails with `Error::VersionConflict` otherwise.
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error>;
+    /// Returns an empty history for a cargo that has never been handled.
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error>;
+    /// Lists the events of all cargos matching the criteria in the order of
+    /// `HandlingEventPosition`.
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error>;
+}
+
+/// OutboxMessage is an appended handling event waiting to be published.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub id: u64,
+    /// The id the event is published with, given when the message is
+    /// recorded so that it is the same on every attempt.
+    #[serde(default)]
+    pub event_id: String,
+    pub event: HandlingEvent,
+}
+
+/// HandlingEventOutbox is implemented by handling event repositories that
+/// record a message for every appended event together with the event, so
+/// an event is never stored without being eventually published. A message
+/// may be published more than once if marking it sent fails.
+#[async_trait]
+pub trait HandlingEventOutbox: Send + Sync {
+    /// Returns up to `limit` unsent messages in the order they were recorded.
+    async fn pending(&self, limit: usize) -> Result<Vec<OutboxMessage>, Error>;
+    /// Marks the message and all messages recorded before it as sent.
+    async fn mark_sent(&self, id: u64) -> Result<(), Error>;
+}
+
+/// HandlingEventCriteria selects handling events. Unset fields match any
+/// event, the completion time window includes its start and excludes its end.
+#[derive(Debug, Clone, Default)]
+pub struct HandlingEventCriteria {
+    pub location: Option<UNLocode>,
+    pub event_type: Option<HandlingEventType>,
+    pub completed_from: Option<DateTime<Utc>>,
+    pub completed_to: Option<DateTime<Utc>>,
 }
 
+impl HandlingEventCriteria {
+    pub fn is_satisfied_by(&self, e: &HandlingEvent) -> bool {
+        self.location
+            .as_ref()
+            .is_none_or(|location| &e.activity.location == location)
+            && self
+                .event_type
+                .as_ref()
+                .is_none_or(|event_type| &e.activity.r#type == event_type)
+            && self
+                .completed_from
+                .is_none_or(|from| e.completion_time >= from)
+            && self.completed_to.is_none_or(|to| e.completion_time < to)
+    }
+}
+
+/// HandlingEventPosition orders handling events of different cargos and
+/// serves as the cursor of `find_handling_events` pages.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HandlingEventPosition {
+    pub completion_time: DateTime<Utc>,
+    pub registration_time: DateTime<Utc>,
+    pub tracking_id: TrackingID,
+}
+
+impl HandlingEventPosition {
+    pub fn of(e: &HandlingEvent) -> Self {
+        HandlingEventPosition {
+            completion_time: e.completion_time,
+            registration_time: e.registration_time,
+            tracking_id: e.tracking_id.clone(),
+        }
+    }
+
+    pub fn to_cursor(&self) -> String {
+        format!(
+            "{}|{}|{}",
+            self.completion_time
+                .to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.registration_time
+                .to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.tracking_id
+        )
+    }
+
+    pub fn from_cursor(cursor: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidField {
+            field: "cursor",
+            description: format!("malformed cursor {}", cursor),
+        };
+        // The tracking id goes last as it may contain the separator.
+        let mut parts = cursor.splitn(3, '|');
+        let mut time = || -> Result<DateTime<Utc>, Error> {
+            let part = parts.next().ok_or_else(invalid)?;
+            DateTime::parse_from_rfc3339(part)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|_| invalid())
+        };
+        let completion_time = time()?;
+        let registration_time = time()?;
+        let tracking_id = parts.next().ok_or_else(invalid)?.to_string();
+        Ok(HandlingEventPosition {
+            completion_time,
+            registration_time,
+            tracking_id,
+        })
+    }
+}
+
+#[async_trait]
 pub trait HandlingEventFactory: Send + Sync {
-    fn create_handling_event(
+    async fn create_handling_event(
         &self,
         registered: DateTime<Utc>,
         completed: DateTime<Utc>,
@@ -56,45 +288,134 @@ impl<C, V, L> HandlingEventFactoryImpl<C, V, L> {
     }
 }
 
+#[async_trait]
 impl<C, V, L> HandlingEventFactory for HandlingEventFactoryImpl<C, V, L>
 where
     C: Repository<TrackingID, Cargo>,
     V: Repository<VoyageNumber, Voyage>,
     L: Repository<UNLocode, Location>,
 {
-    fn create_handling_event(
+    async fn create_handling_event(
         &self,
-        _registered: DateTime<Utc>,
-        _completed: DateTime<Utc>,
+        registered: DateTime<Utc>,
+        completed: DateTime<Utc>,
         id: TrackingID,
         voyage_number: VoyageNumber,
         un_locode: UNLocode,
         event_type: HandlingEventType,
     ) -> Result<HandlingEvent, Error> {
-        self.cargo_repository.find(id.clone())?;
-        // When creating a Receive event, the voyage number is not known.
+        validate_fields(&id, &voyage_number, &un_locode, &event_type)?;
+        let cargo = self
+            .cargo_repository
+            .find(id.clone())
+            .await
+            .map_err(|err| unknown(err, Error::UnknownCargo(id.clone())))?;
         if !voyage_number.is_empty() {
-            self.voyage_repository.find(voyage_number.clone())?;
+            self.voyage_repository
+                .find(voyage_number.clone())
+                .await
+                .map_err(|err| unknown(err, Error::UnknownVoyage(voyage_number.clone())))?;
         }
-        self.location_repository.find(un_locode.clone())?;
+        self.location_repository
+            .find(un_locode.clone())
+            .await
+            .map_err(|err| unknown(err, Error::UnknownLocation(un_locode.clone())))?;
 
-        Ok(HandlingEvent {
+        let mut e = HandlingEvent {
             tracking_id: id,
             activity: HandlingActivity {
                 r#type: event_type,
                 location: un_locode,
-                voyage_number: voyage_number,
+                voyage_number,
             },
-        })
+            registration_time: registered,
+            completion_time: completed,
+            is_expected: true,
+        };
+        e.is_expected = cargo.itinerary.is_expected(&e);
+        Ok(e)
+    }
+}
+
+/// Replaces a generic repository miss with an error naming the missing entity.
+pub fn unknown(err: Error, unknown: Error) -> Error {
+    match err {
+        Error::NotFound(_) => unknown,
+        err => err,
+    }
+}
+
+// Only the carrier movements (Load and Unload) are performed on a voyage.
+fn validate_fields(
+    id: &str,
+    voyage_number: &str,
+    un_locode: &str,
+    event_type: &HandlingEventType,
+) -> Result<(), Error> {
+    let invalid = |field, description: String| Err(Error::InvalidField { field, description });
+    if id.is_empty() {
+        return invalid("id", "tracking id is required".to_string());
+    }
+    if un_locode.is_empty() {
+        return invalid("un_locode", "location is required".to_string());
+    }
+    match event_type {
+        HandlingEventType::NotHandled => invalid(
+            "event_type",
+            "NotHandled event can not be registered".to_string(),
+        ),
+        HandlingEventType::Load | HandlingEventType::Unload if voyage_number.is_empty() => invalid(
+            "voyage_number",
+            format!("{:?} event requires a voyage", event_type),
+        ),
+        HandlingEventType::Receive | HandlingEventType::Claim | HandlingEventType::Customs
+            if !voyage_number.is_empty() =>
+        {
+            invalid(
+                "voyage_number",
+                format!("{:?} event can not have a voyage", event_type),
+            )
+        }
+        _ => Ok(()),
     }
 }
 
 pub type TrackingID = String;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Cargo {
     pub tracking_id: TrackingID,
     pub origin: UNLocode,
     pub destination: UNLocode,
     pub arrival_deadline: DateTime<Utc>,
+    pub itinerary: Itinerary,
+}
+
+/// CargoCriteria selects cargos. Unset fields match any cargo.
+#[derive(Debug, Clone, Default)]
+pub struct CargoCriteria {
+    pub origin: Option<UNLocode>,
+    pub destination: Option<UNLocode>,
+}
+
+impl CargoCriteria {
+    pub fn is_satisfied_by(&self, cargo: &Cargo) -> bool {
+        self.origin
+            .as_ref()
+            .is_none_or(|origin| &cargo.origin == origin)
+            && self
+                .destination
+                .as_ref()
+                .is_none_or(|destination| &cargo.destination == destination)
+    }
+}
+
+#[async_trait]
+pub trait CargoRepository: Repository<TrackingID, Cargo> {
+    /// Lists the matching cargos ordered by tracking id.
+    async fn find_by(
+        &self,
+        criteria: CargoCriteria,
+        page: PageRequest,
+    ) -> Result<Page<Cargo>, Error>;
 }
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// How many message IDs an inbox remembers to recognize redeliveries.
+pub const REMEMBERED_MESSAGES: usize = 100;
+
+/// CargoInbox records the integration events handled for a cargo, so that
+/// redelivered and outdated ones can be skipped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CargoInbox {
+    /// The sequence number of the latest handled event of each type that had
+    /// one. An event only outdates older events of its type.
+    pub sequences: BTreeMap<String, u64>,
+    /// The IDs of the latest handled messages, oldest first.
+    pub message_ids: VecDeque<String>,
+    /// The digest of the latest handled message of each type that had no ID.
+    /// Only the latest one counts, as an event may repeat an earlier one,
+    /// such as a destination changed back.
+    #[serde(default)]
+    pub digests: BTreeMap<String, u64>,
+}
+
+impl CargoInbox {
+    /// Tells whether the message was handled already.
+    pub fn is_duplicate(&self, message_id: Option<&str>) -> bool {
+        message_id.is_some_and(|id| self.message_ids.iter().any(|handled| handled == id))
+    }
+
+    /// Tells whether the message without an ID repeats the latest handled
+    /// message of its type.
+    pub fn is_redelivered(&self, kind: &str, digest: Option<u64>) -> bool {
+        digest.is_some_and(|digest| self.digests.get(kind) == Some(&digest))
+    }
+
+    /// Tells whether a later event of the type was handled already.
+    pub fn is_stale(&self, kind: &str, sequence: Option<u64>) -> bool {
+        match (sequence, self.sequences.get(kind)) {
+            (Some(sequence), Some(handled)) => sequence <= *handled,
+            _ => false,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        message_id: Option<&str>,
+        kind: &str,
+        sequence: Option<u64>,
+        digest: Option<u64>,
+    ) {
+        if let Some(id) = message_id {
+            if self.message_ids.len() == REMEMBERED_MESSAGES {
+                self.message_ids.pop_front();
+            }
+            self.message_ids.push_back(id.to_string());
+        }
+        if let Some(sequence) = sequence {
+            let handled = self.sequences.entry(kind.to_string()).or_default();
+            *handled = sequence.max(*handled);
+        }
+        if let Some(digest) = digest {
+            self.digests.insert(kind.to_string(), digest);
+        }
+    }
+}
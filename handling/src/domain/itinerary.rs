@@ -0,0 +1,59 @@
+use super::handling::{HandlingEvent, HandlingEventType};
+use super::location::UNLocode;
+use super::voyage::VoyageNumber;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Leg describes the transportation between two locations on a voyage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Leg {
+    pub voyage_number: VoyageNumber,
+    pub load_location: UNLocode,
+    pub unload_location: UNLocode,
+    pub load_time: DateTime<Utc>,
+    pub unload_time: DateTime<Utc>,
+}
+
+// Itinerary specifies steps required to transport a cargo from its origin to
+// destination.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Itinerary {
+    pub legs: Vec<Leg>,
+}
+
+impl Itinerary {
+    pub fn is_empty(&self) -> bool {
+        self.legs.is_empty()
+    }
+
+    pub fn initial_departure_location(&self) -> Option<&UNLocode> {
+        self.legs.first().map(|l| &l.load_location)
+    }
+
+    pub fn final_arrival_location(&self) -> Option<&UNLocode> {
+        self.legs.last().map(|l| &l.unload_location)
+    }
+
+    /// Checks if the given handling event is expected when executing this
+    /// itinerary. Any event is expected while the cargo is not routed.
+    pub fn is_expected(&self, e: &HandlingEvent) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let activity = &e.activity;
+        match activity.r#type {
+            HandlingEventType::Receive => {
+                self.initial_departure_location() == Some(&activity.location)
+            }
+            HandlingEventType::Load => self.legs.iter().any(|l| {
+                l.load_location == activity.location && l.voyage_number == activity.voyage_number
+            }),
+            HandlingEventType::Unload => self.legs.iter().any(|l| {
+                l.unload_location == activity.location && l.voyage_number == activity.voyage_number
+            }),
+            HandlingEventType::Claim => self.final_arrival_location() == Some(&activity.location),
+            HandlingEventType::NotHandled | HandlingEventType::Customs => true,
+        }
+    }
+}
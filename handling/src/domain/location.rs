@@ -1,5 +1,6 @@
 use super::Repository;
 use crate::Error;
+use serde::{Deserialize, Serialize};
 
 // UNLocode is the United Nations location code that uniquely identifies a
 // particular location.
@@ -10,14 +11,28 @@ pub type UNLocode = String;
 
 // Location is a location is our model is stops on a journey, such as cargo
 // origin or destination, or carrier movement endpoints.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Location {
     un_locode: UNLocode,
     name: String,
 }
 
+impl Location {
+    pub fn new(un_locode: UNLocode, name: String) -> Self {
+        Location { un_locode, name }
+    }
+
+    pub fn un_locode(&self) -> &UNLocode {
+        &self.un_locode
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 #[allow(non_snake_case)]
-pub fn store_sample_locations<R: Repository<UNLocode, Location>>(
+pub async fn store_sample_locations<R: Repository<UNLocode, Location>>(
     repository: &R,
 ) -> Result<(), Error> {
     let SESTO = &Location {
@@ -72,19 +87,19 @@ pub fn store_sample_locations<R: Repository<UNLocode, Location>>(
         un_locode: "FIHEL".to_string(),
         name: "Helsinki".to_string(),
     };
-    repository.store(SESTO.un_locode.clone(), SESTO)?;
-    repository.store(SEGOT.un_locode.clone(), SEGOT)?;
-    repository.store(AUMEL.un_locode.clone(), AUMEL)?;
-    repository.store(CNHKG.un_locode.clone(), CNHKG)?;
-    repository.store(CNSHA.un_locode.clone(), CNSHA)?;
-    repository.store(CNHGH.un_locode.clone(), CNHGH)?;
-    repository.store(USNYC.un_locode.clone(), USNYC)?;
-    repository.store(USCHI.un_locode.clone(), USCHI)?;
-    repository.store(USDAL.un_locode.clone(), USDAL)?;
-    repository.store(JNTKO.un_locode.clone(), JNTKO)?;
-    repository.store(DEHAM.un_locode.clone(), DEHAM)?;
-    repository.store(NLRTM.un_locode.clone(), NLRTM)?;
-    repository.store(FIHEL.un_locode.clone(), FIHEL)?;
+    repository.store(SESTO.un_locode.clone(), SESTO).await?;
+    repository.store(SEGOT.un_locode.clone(), SEGOT).await?;
+    repository.store(AUMEL.un_locode.clone(), AUMEL).await?;
+    repository.store(CNHKG.un_locode.clone(), CNHKG).await?;
+    repository.store(CNSHA.un_locode.clone(), CNSHA).await?;
+    repository.store(CNHGH.un_locode.clone(), CNHGH).await?;
+    repository.store(USNYC.un_locode.clone(), USNYC).await?;
+    repository.store(USCHI.un_locode.clone(), USCHI).await?;
+    repository.store(USDAL.un_locode.clone(), USDAL).await?;
+    repository.store(JNTKO.un_locode.clone(), JNTKO).await?;
+    repository.store(DEHAM.un_locode.clone(), DEHAM).await?;
+    repository.store(NLRTM.un_locode.clone(), NLRTM).await?;
+    repository.store(FIHEL.un_locode.clone(), FIHEL).await?;
 
     Ok(())
 }
@@ -1,16 +1,127 @@
 pub mod handling;
+pub mod inbox;
+pub mod itinerary;
 pub mod location;
+pub mod parking;
 pub mod voyage;
 
 use crate::Error;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::hash::Hash;
 
+#[async_trait]
 pub trait Repository<K, V>: Clone + Send + Sync
 where
-    K: Eq + Hash + std::fmt::Display + Clone + Send,
-    V: Clone + Send,
+    K: Eq + Hash + std::fmt::Display + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
 {
-    fn store(&self, id: K, v: &V) -> Result<(), Error>;
-    fn find(&self, id: K) -> Result<V, Error>;
-    fn find_all(&self) -> Result<Vec<V>, Error>;
+    async fn store(&self, id: K, v: &V) -> Result<(), Error>;
+    async fn find(&self, id: K) -> Result<V, Error>;
+    async fn find_versioned(&self, id: K) -> Result<Versioned<V>, Error>;
+    /// Stores the entity only if the stored one still has the `expected`
+    /// version, or if there is none when `expected` is None. Returns the new
+    /// version or fails with `Error::VersionConflict`.
+    async fn store_if_version(
+        &self,
+        id: K,
+        v: &V,
+        expected: Option<Version>,
+    ) -> Result<Version, Error>;
+    async fn find_all(&self) -> Result<Vec<V>, Error>;
+    async fn exists(&self, id: K) -> Result<bool, Error>;
+    /// Fails with `Error::NotFound` if there is nothing to delete.
+    async fn delete(&self, id: K) -> Result<(), Error>;
+    /// Lists entities ordered by the string form of their keys.
+    async fn find_page(&self, page: PageRequest) -> Result<Page<V>, Error>;
+}
+
+/// Version counts the updates of a stored entity, the first one stores
+/// version 1.
+pub type Version = u64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Versioned<V> {
+    pub value: V,
+    pub version: Version,
+}
+
+/// How many times an optimistic update is attempted before a conflict is
+/// reported to the caller.
+pub const MAX_UPDATE_ATTEMPTS: usize = 3;
+
+/// Runs an optimistic read-modify-write again while it conflicts with a
+/// concurrent update, at most `MAX_UPDATE_ATTEMPTS` times.
+pub async fn retry_on_conflict<T, F, Fut>(mut update: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match update().await {
+            Err(Error::VersionConflict(_)) if attempt < MAX_UPDATE_ATTEMPTS => attempt += 1,
+            res => return res,
+        }
+    }
+}
+
+/// PageRequest asks for at most `limit` entities following the `cursor`
+/// returned with the previous page. Cursors are opaque to callers.
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+impl PageRequest {
+    pub fn first(limit: usize) -> Self {
+        PageRequest {
+            cursor: None,
+            limit,
+        }
+    }
+
+    /// Returns the request for the page following `page`, if there is one.
+    pub fn next<V>(&self, page: &Page<V>) -> Option<Self> {
+        page.next_cursor.as_ref().map(|cursor| PageRequest {
+            cursor: Some(cursor.clone()),
+            limit: self.limit,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.limit == 0 {
+            return Err(Error::InvalidField {
+                field: "limit",
+                description: "page size must be positive".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Page<V> {
+    pub items: Vec<V>,
+    /// Is None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+impl<V> Page<V> {
+    /// Builds a page from up to `limit + 1` ordered entities paired with their
+    /// cursors. The extra entity only tells that another page follows.
+    pub fn from_ordered(mut entries: Vec<(String, V)>, limit: usize) -> Self {
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(cursor, _)| cursor.clone())
+        } else {
+            None
+        };
+        Page {
+            items: entries.into_iter().map(|(_, v)| v).collect(),
+            next_cursor,
+        }
+    }
 }
@@ -0,0 +1,43 @@
+use crate::domain::handling::TrackingID;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// ParkedEvent is an integration event held back until its cargo is known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParkedEvent {
+    /// The type name of the event.
+    pub kind: String,
+    /// The event as it was encoded in the message.
+    pub payload: Vec<u8>,
+    /// The metadata of the message, by attribute name.
+    pub attributes: Vec<(String, String)>,
+    pub parked_at: DateTime<Utc>,
+}
+
+/// DeadLetter is a parked event that was given up on, because its cargo
+/// stayed unknown for too long or because it failed once replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub event: ParkedEvent,
+    pub reason: String,
+    pub dead_lettered_at: DateTime<Utc>,
+}
+
+/// ParkingLot holds the parked events of a cargo in the order they arrived,
+/// and the dead letters among them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParkingLot {
+    pub tracking_id: TrackingID,
+    pub parked: Vec<ParkedEvent>,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+impl ParkingLot {
+    pub fn new(tracking_id: TrackingID) -> Self {
+        ParkingLot {
+            tracking_id,
+            parked: Vec::new(),
+            dead_letters: Vec::new(),
+        }
+    }
+}
@@ -1,26 +1,68 @@
+use crate::domain::handling::{HandlingEventType, TrackingID, TransportStatus};
+use crate::domain::location::UNLocode;
+use crate::domain::voyage::VoyageNumber;
 use std::{error, fmt};
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    InvalidArgument,
+    UnknownCargo(TrackingID),
+    UnknownVoyage(VoyageNumber),
+    UnknownLocation(UNLocode),
+    InvalidField {
+        field: &'static str,
+        description: String,
+    },
     HandlingError,
+    IllegalTransition {
+        tracking_id: TrackingID,
+        status: TransportStatus,
+        event_type: HandlingEventType,
+    },
+    NotFound(String),
+    /// An optimistic update found the entity changed since it was read.
+    VersionConflict(String),
     RepositoryError(String),
     ParsingError,
     EncodeError(prost::EncodeError),
     DecodeError(prost::DecodeError),
+    #[cfg(feature = "amqp")]
     LapinError(lapin::Error),
+    #[cfg(feature = "nats")]
+    NatsError(String),
+    /// The broker did not take responsibility for a published message.
+    PublishError(String),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::InvalidArgument => write!(f, "Provided argument is invalid"),
+            Error::UnknownCargo(id) => write!(f, "Unknown cargo {}", id),
+            Error::UnknownVoyage(number) => write!(f, "Unknown voyage {}", number),
+            Error::UnknownLocation(code) => write!(f, "Unknown location {}", code),
+            Error::InvalidField { field, description } => {
+                write!(f, "Invalid {}: {}", field, description)
+            }
             Error::HandlingError => write!(f, "Event processing error"),
+            Error::IllegalTransition {
+                tracking_id,
+                status,
+                event_type,
+            } => write!(
+                f,
+                "Cargo {} in status {:?} can not be handled with {:?}",
+                tracking_id, status, event_type
+            ),
+            Error::NotFound(key) => write!(f, "Not found: {}", key),
+            Error::VersionConflict(key) => write!(f, "Concurrent update of {}", key),
             Error::RepositoryError(msg) => write!(f, "Repository error: {}", msg),
             Error::ParsingError => write!(f, "Parsing error"),
-            Error::EncodeError(err) => write!(f, "{}", err.to_string()),
-            Error::DecodeError(err) => write!(f, "{}", err.to_string()),
-            Error::LapinError(err) => write!(f, "{}", err.to_string()),
+            Error::EncodeError(err) => write!(f, "{}", err),
+            Error::DecodeError(err) => write!(f, "{}", err),
+            #[cfg(feature = "amqp")]
+            Error::LapinError(err) => write!(f, "{}", err),
+            #[cfg(feature = "nats")]
+            Error::NatsError(msg) => write!(f, "NATS error: {}", msg),
+            Error::PublishError(msg) => write!(f, "Publish error: {}", msg),
         }
     }
 }
@@ -39,8 +81,15 @@ impl From<prost::DecodeError> for Error {
     }
 }
 
+#[cfg(feature = "amqp")]
 impl From<lapin::Error> for Error {
     fn from(value: lapin::Error) -> Self {
         Error::LapinError(value)
     }
 }
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::RepositoryError(value.to_string())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::application::envelope::Envelope;
+use crate::application::integration_events::{EventHandler, OrderedSpawner, Task};
+use crate::application::pb::OrderingKey;
+use crate::Error;
+use bytes::Bytes;
+use prost::Message;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+/// EventBusConfig sets how the event bus handles failing messages.
+#[derive(Debug, Clone)]
+pub struct EventBusConfig {
+    /// Attempts to handle a message after the first one failed, before it is
+    /// moved to the dead-letter queue.
+    pub max_retries: u32,
+    /// The time a failed message waits before it is handled again.
+    pub retry_delay: Duration,
+    /// Messages delivered before they are acknowledged, which bounds the
+    /// number of messages handled concurrently.
+    pub prefetch_count: u16,
+}
+
+impl Default for EventBusConfig {
+    fn default() -> Self {
+        EventBusConfig {
+            max_retries: 5,
+            retry_delay: Duration::from_secs(5),
+            prefetch_count: 32,
+        }
+    }
+}
+
+pub(crate) type Handling = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+// Decodes a message and returns its ordering key with the handling of it.
+pub(crate) type HandlerFunc =
+    Box<dyn Fn(Vec<u8>, Envelope) -> Result<(String, Handling), Error> + Send + Sync>;
+
+pub(crate) fn handler_func<E, EH>(eh: EH) -> HandlerFunc
+where
+    E: Message + OrderingKey + Default + 'static,
+    EH: EventHandler<E> + 'static,
+{
+    let eh = Arc::new(eh);
+    Box::new(move |data: Vec<u8>, envelope: Envelope| {
+        let e: E = Message::decode(Bytes::from(data))?;
+        let key = e.ordering_key().to_string();
+        let eh = eh.clone();
+        let handling: Handling = Box::pin(async move { eh.handle_message(e, envelope).await });
+        Ok((key, handling))
+    })
+}
+
+// OrderedTasks runs every message in its own task, once the messages spawned
+// before it with the same ordering key are handled. Clones share the order,
+// so tasks spawned besides the consumer queue up with the messages of their
+// key.
+#[derive(Clone, Default)]
+pub(crate) struct OrderedTasks {
+    // The completion of the last task of each ordering key in flight.
+    in_flight: Arc<Mutex<HashMap<String, oneshot::Receiver<()>>>>,
+}
+
+impl OrderedTasks {
+    pub(crate) fn spawn<T>(&self, key: String, task: T)
+    where
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let (previous, done_tx) = {
+            // Nothing panics while the lock is held.
+            let mut in_flight = self
+                .in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            in_flight.retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+            let previous = in_flight.remove(&key);
+            let (done_tx, done_rx) = oneshot::channel();
+            in_flight.insert(key, done_rx);
+            (previous, done_tx)
+        };
+        tokio::spawn(async move {
+            if let Some(previous) = previous {
+                // Fails if the previous task panicked, which is as done.
+                let _ = previous.await;
+            }
+            task.await;
+            let _ = done_tx.send(());
+        });
+    }
+
+    pub(crate) fn spawner(&self) -> OrderedSpawner {
+        let tasks = self.clone();
+        Arc::new(move |key: String, task: Task| tasks.spawn(key, task))
+    }
+}
@@ -0,0 +1,331 @@
+use super::inmem_repository::InmemRepository;
+use crate::application::envelope::new_id;
+use crate::domain::handling::{
+    HandlingEvent, HandlingEventCriteria, HandlingEventOutbox, HandlingEventRepository,
+    HandlingHistory, OutboxMessage, TrackingID,
+};
+use crate::domain::{Page, PageRequest, Version};
+use crate::Error;
+use async_trait::async_trait;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// Each record is the length and the CRC-32 of its payload, both little
+// endian u32, followed by the payload: a JSON encoded `Record`.
+const HEADER_LEN: usize = 8;
+// Guards against allocating a garbage length read from a damaged header.
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+// A handling event with the id of its message. Records written before
+// messages had ids are the bare event.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    event: HandlingEvent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+}
+
+// Projection is a read model of the handling event log. Projections are
+// rebuilt by replaying the log on startup and then kept up to date with
+// every appended event, so a new one needs no migration. The handling
+// histories are the only one so far.
+pub(crate) trait Projection: Send + Sync {
+    fn apply(&self, e: &HandlingEvent) -> Result<(), Error>;
+}
+
+/// HandlingEventLog is an event-sourced handling event repository. Events
+/// are only ever appended to a checksummed log file, the handling histories
+/// are a projection of that log.
+///
+/// The log is also the outbox of the events: the id of a message is the
+/// position of its record in the log, the record keeps the id the event is
+/// published with, and the id of the last sent message is kept in a file
+/// next to the log, named after it with a `.sent` suffix.
+#[derive(Clone)]
+pub struct HandlingEventLog {
+    writer: Arc<Mutex<Writer>>,
+    histories: InmemRepository<TrackingID, HandlingHistory>,
+    outbox: Arc<Mutex<Outbox>>,
+}
+
+struct Writer {
+    file: tokio::fs::File,
+    // The length of the log up to the last complete record.
+    len: u64,
+    records: u64,
+}
+
+struct Outbox {
+    sent_path: PathBuf,
+    sent: u64,
+    pending: VecDeque<OutboxMessage>,
+}
+
+impl HandlingEventLog {
+    /// Opens the log, creating it if needed, and replays it into the handling
+    /// histories. A record torn by a crash while it was written is discarded,
+    /// any other damage fails the replay.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let log_error = |err: io::Error| log_error(path, err);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(log_error)?;
+        let sent_path = sent_path(path);
+        let sent = read_sent(&sent_path)?;
+        let histories = InmemRepository::new();
+        let mut records = 0;
+        let mut pending = VecDeque::new();
+        let replayed = replay(&mut file, |record| {
+            let e = &record.event;
+            histories.apply(e)?;
+            records += 1;
+            if records > sent {
+                pending.push_back(OutboxMessage {
+                    id: records,
+                    // Stable across restarts for records without an id.
+                    event_id: record
+                        .event_id
+                        .clone()
+                        .unwrap_or_else(|| format!("{}-{}", e.tracking_id, records)),
+                    event: e.clone(),
+                });
+            }
+            Ok(())
+        })
+        .map_err(|err| match err {
+            ReplayError::Io(err) => log_error(err),
+            ReplayError::Corrupted(offset) => Error::RepositoryError(format!(
+                "handling log {}: corrupted record at offset {}",
+                path.display(),
+                offset
+            )),
+            ReplayError::Apply(err) => err,
+        })?;
+        let len = file.metadata().map_err(log_error)?.len();
+        if replayed < len {
+            warn!(
+                "Discarding {} bytes of an incomplete record at the end of {}",
+                len - replayed,
+                path.display()
+            );
+            file.set_len(replayed).map_err(log_error)?;
+        }
+        Ok(HandlingEventLog {
+            writer: Arc::new(Mutex::new(Writer {
+                file: tokio::fs::File::from_std(file),
+                len: replayed,
+                records,
+            })),
+            histories,
+            outbox: Arc::new(Mutex::new(Outbox {
+                sent_path,
+                // A mark past the end of the log is left over from a log that
+                // has since been replaced.
+                sent: sent.min(records),
+                pending,
+            })),
+        })
+    }
+}
+
+enum ReplayError {
+    Io(io::Error),
+    Corrupted(u64),
+    Apply(Error),
+}
+
+// Returns the length of the log up to the last complete record.
+fn replay<F>(file: &mut File, mut apply: F) -> Result<u64, ReplayError>
+where
+    F: FnMut(&Record) -> Result<(), Error>,
+{
+    let mut reader = BufReader::new(file);
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        if !read_record_part(&mut reader, &mut header)? {
+            return Ok(offset);
+        }
+        let mut len = [0u8; 4];
+        let mut checksum = [0u8; 4];
+        len.copy_from_slice(&header[..4]);
+        checksum.copy_from_slice(&header[4..]);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(ReplayError::Corrupted(offset));
+        }
+        let mut payload = vec![0u8; len];
+        if !read_record_part(&mut reader, &mut payload)? {
+            return Ok(offset);
+        }
+        if crc32fast::hash(&payload) != u32::from_le_bytes(checksum) {
+            return Err(ReplayError::Corrupted(offset));
+        }
+        let record: Record =
+            serde_json::from_slice(&payload).map_err(|_| ReplayError::Corrupted(offset))?;
+        apply(&record).map_err(ReplayError::Apply)?;
+        offset += (HEADER_LEN + len) as u64;
+    }
+}
+
+// Fills the buffer, returning false if the log ends before it is full.
+fn read_record_part<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, ReplayError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(ReplayError::Io(err)),
+        }
+    }
+    Ok(true)
+}
+
+fn encode_record(record: &Record) -> Result<Vec<u8>, Error> {
+    let payload =
+        serde_json::to_vec(record).map_err(|err| Error::RepositoryError(err.to_string()))?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+fn sent_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sent");
+    path.with_file_name(name)
+}
+
+// Returns the id of the last sent message, 0 if none was sent yet.
+fn read_sent(path: &Path) -> Result<u64, Error> {
+    match fs::read_to_string(path) {
+        Ok(sent) => sent.trim().parse().map_err(|err| {
+            Error::RepositoryError(format!("handling log {}: {}", path.display(), err))
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(log_error(path, err)),
+    }
+}
+
+fn log_error(path: &Path, err: io::Error) -> Error {
+    Error::RepositoryError(format!("handling log {}: {}", path.display(), err))
+}
+
+impl HandlingEventLog {
+    async fn append_checked(
+        &self,
+        e: &HandlingEvent,
+        expected: Option<Version>,
+    ) -> Result<(), Error> {
+        // Appends are serialized, so an event is validated against the same
+        // history it is appended to.
+        let mut writer = self.writer.lock().await;
+        let mut history = self
+            .histories
+            .query_handling_history(e.tracking_id.clone())
+            .await?;
+        if expected.is_some_and(|expected| expected != history.version()) {
+            return Err(Error::VersionConflict(e.tracking_id.clone()));
+        }
+        history.append(e.clone())?;
+
+        let event_id = new_id();
+        let record = encode_record(&Record {
+            event: e.clone(),
+            event_id: Some(event_id.clone()),
+        })?;
+        let written = async {
+            writer.file.write_all(&record).await?;
+            writer.file.sync_data().await
+        }
+        .await;
+        if let Err(err) = written {
+            // Cut off what was written of the record, the next append would
+            // otherwise follow a damaged record.
+            let len = writer.len;
+            let _ = writer.file.set_len(len).await;
+            return Err(Error::RepositoryError(format!("handling log: {}", err)));
+        }
+        writer.len += record.len() as u64;
+        writer.records += 1;
+        // Recorded while appends are still serialized, so messages are in
+        // the order of the log.
+        self.outbox.lock().await.pending.push_back(OutboxMessage {
+            id: writer.records,
+            event_id,
+            event: e.clone(),
+        });
+
+        // The event is durable at this point. A projection failing to apply
+        // it is caught up by the replay on the next start.
+        if let Err(err) = self.histories.apply(e) {
+            error!("Failed to project handling event: {}", err);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HandlingEventRepository for HandlingEventLog {
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error> {
+        self.append_checked(e, None).await
+    }
+
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error> {
+        self.append_checked(e, Some(expected)).await
+    }
+
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error> {
+        self.histories.query_handling_history(id).await
+    }
+
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error> {
+        self.histories.find_handling_events(criteria, page).await
+    }
+}
+
+#[async_trait]
+impl HandlingEventOutbox for HandlingEventLog {
+    async fn pending(&self, limit: usize) -> Result<Vec<OutboxMessage>, Error> {
+        let outbox = self.outbox.lock().await;
+        Ok(outbox.pending.iter().take(limit).cloned().collect())
+    }
+
+    async fn mark_sent(&self, id: u64) -> Result<(), Error> {
+        let mut outbox = self.outbox.lock().await;
+        if id <= outbox.sent {
+            return Ok(());
+        }
+        // Replaced rather than overwritten, so a crash leaves either mark.
+        let mut tmp_path = outbox.sent_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let written = async {
+            tokio::fs::write(&tmp_path, id.to_string()).await?;
+            tokio::fs::rename(&tmp_path, &outbox.sent_path).await
+        }
+        .await;
+        written.map_err(|err| log_error(&outbox.sent_path, err))?;
+        outbox.sent = id;
+        while outbox.pending.front().is_some_and(|m| m.id <= id) {
+            outbox.pending.pop_front();
+        }
+        Ok(())
+    }
+}
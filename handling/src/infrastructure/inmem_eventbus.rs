@@ -0,0 +1,145 @@
+use super::eventbus::{handler_func, HandlerFunc, OrderedTasks};
+use crate::application::envelope::{new_id, Envelope};
+use crate::application::integration_events::{
+    EventHandler, EventService, OrderedSpawner, SubscribeManager,
+};
+use crate::application::pb::{HandlingEvent as PbHandlingEvent, OrderingKey, TypeName};
+use crate::domain::handling::HandlingEvent;
+use crate::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+/// PublishedMessage is a message published on an `InmemEventBus`.
+#[derive(Debug, Clone)]
+pub struct PublishedMessage {
+    pub kind: String,
+    pub envelope: Envelope,
+    pub payload: Vec<u8>,
+}
+
+/// InmemEventBus delivers integration events to the handlers subscribed in
+/// the same process, which lets the service run without a broker. Messages
+/// are encoded as they would be on a broker.
+///
+/// Handlers run before `publish` returns, and their first error is returned
+/// from it. They run in order with the other messages of their cargo, so a
+/// handler must not publish a message of its own cargo.
+#[derive(Clone, Default)]
+pub struct InmemEventBus {
+    handlers: Arc<Mutex<HashMap<String, Vec<Arc<HandlerFunc>>>>>,
+    tasks: OrderedTasks,
+    // None unless the bus records the published messages.
+    published: Option<Arc<Mutex<Vec<PublishedMessage>>>>,
+}
+
+impl InmemEventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a bus that records every published message, so tests can
+    /// assert on what was published. The records are never dropped, so this
+    /// is not meant for a running service.
+    pub fn recording() -> Self {
+        InmemEventBus {
+            published: Some(Arc::default()),
+            ..Self::default()
+        }
+    }
+
+    /// Publishes a new message.
+    pub async fn publish<E>(&self, e: &E) -> Result<(), Error>
+    where
+        E: Message + TypeName + OrderingKey,
+    {
+        self.publish_with_id(e, new_id()).await
+    }
+
+    /// Publishes the message `id`, which may have been published before.
+    pub async fn publish_with_id<E>(&self, e: &E, id: String) -> Result<(), Error>
+    where
+        E: Message + TypeName + OrderingKey,
+    {
+        let mut payload = vec![];
+        e.encode(&mut payload)?;
+        let envelope = Envelope::new(e, id);
+        if let Some(published) = &self.published {
+            lock(published)?.push(PublishedMessage {
+                kind: E::name().to_string(),
+                envelope: envelope.clone(),
+                payload: payload.clone(),
+            });
+        }
+        // Not held while handling, handlers may publish themselves.
+        let handlers = lock(&self.handlers)?
+            .get(E::name())
+            .cloned()
+            .unwrap_or_default();
+        for handler in handlers {
+            let (key, handling) = handler(payload.clone(), envelope.clone())?;
+            let (done_tx, done_rx) = oneshot::channel();
+            self.tasks.spawn(key, async move {
+                let _ = done_tx.send(handling.await);
+            });
+            // Fails if the handler panicked.
+            done_rx.await.map_err(|_| Error::HandlingError)??;
+        }
+        Ok(())
+    }
+
+    /// Returns all published messages in the order they were published, none
+    /// unless the bus was created with `recording`.
+    pub fn published_messages(&self) -> Vec<PublishedMessage> {
+        self.published
+            .as_ref()
+            .and_then(|published| lock(published).ok())
+            .map(|published| published.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the published messages of type `E`.
+    pub fn published<E: Message + TypeName + Default>(&self) -> Result<Vec<E>, Error> {
+        self.published_messages()
+            .into_iter()
+            .filter(|message| message.kind == E::name())
+            .map(|message| Ok(E::decode(Bytes::from(message.payload))?))
+            .collect()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Error> {
+    mutex
+        .lock()
+        .map_err(|_| Error::RepositoryError("event bus lock is poisoned".to_string()))
+}
+
+#[async_trait]
+impl EventService for InmemEventBus {
+    async fn cargo_was_handled(&self, e: HandlingEvent, id: String) -> Result<(), Error> {
+        let pb_event: PbHandlingEvent = e.into();
+        self.publish_with_id(&pb_event, id).await
+    }
+}
+
+#[async_trait]
+impl SubscribeManager for InmemEventBus {
+    async fn subscribe<E, EH>(&mut self, eh: EH) -> Result<(), Box<dyn std::error::Error>>
+    where
+        E: Message + TypeName + OrderingKey + Default + 'static,
+        EH: EventHandler<E> + 'static,
+    {
+        lock(&self.handlers)?
+            .entry(E::name().to_string())
+            .or_default()
+            .push(Arc::new(handler_func(eh)));
+        Ok(())
+    }
+
+    fn ordered_spawner(&self) -> OrderedSpawner {
+        self.tasks.spawner()
+    }
+}
@@ -0,0 +1,132 @@
+use crate::application::envelope::new_id;
+use crate::domain::handling::{
+    HandlingEvent, HandlingEventCriteria, HandlingEventOutbox, HandlingEventRepository,
+    HandlingHistory, OutboxMessage, TrackingID,
+};
+use crate::domain::{Page, PageRequest, Version};
+use crate::Error;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// InmemOutbox records a message in memory for every handling event appended
+/// to the wrapped repository. It is meant for in-memory repositories, whose
+/// events are exactly as durable as the messages: both are lost on a crash
+/// and both are kept in snapshots.
+#[derive(Clone)]
+pub struct InmemOutbox<R> {
+    handling_events: R,
+    // Appends are serialized so that messages are recorded in the order
+    // their events are appended.
+    append_lock: Arc<tokio::sync::Mutex<()>>,
+    messages: Arc<Mutex<Messages>>,
+}
+
+struct Messages {
+    pending: VecDeque<OutboxMessage>,
+    last_id: u64,
+}
+
+impl<R> InmemOutbox<R> {
+    pub fn new(handling_events: R) -> Self {
+        Self::with_pending(handling_events, Vec::new())
+    }
+
+    pub(crate) fn with_pending(handling_events: R, mut pending: Vec<OutboxMessage>) -> Self {
+        pending.sort_by_key(|m| m.id);
+        // Messages of snapshots taken before they had event ids.
+        for message in pending.iter_mut().filter(|m| m.event_id.is_empty()) {
+            message.event_id = new_id();
+        }
+        let last_id = pending.last().map_or(0, |m| m.id);
+        InmemOutbox {
+            handling_events,
+            append_lock: Arc::default(),
+            messages: Arc::new(Mutex::new(Messages {
+                pending: pending.into(),
+                last_id,
+            })),
+        }
+    }
+
+    /// Copies the wrapped repository with `copy`, and the pending messages,
+    /// while no event is being appended. Every copied event thus has its
+    /// message, unless the message was sent already.
+    pub(crate) async fn contents<T, F>(&self, copy: F) -> Result<(T, Vec<OutboxMessage>), Error>
+    where
+        F: FnOnce(&R) -> Result<T, Error>,
+    {
+        let _guard = self.append_lock.lock().await;
+        let copied = copy(&self.handling_events)?;
+        let pending = self.lock()?.pending.iter().cloned().collect();
+        Ok((copied, pending))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Messages>, Error> {
+        self.messages
+            .lock()
+            .map_err(|_| Error::RepositoryError("outbox lock is poisoned".to_string()))
+    }
+
+    fn record(&self, e: &HandlingEvent) -> Result<(), Error> {
+        let mut messages = self.lock()?;
+        messages.last_id += 1;
+        let id = messages.last_id;
+        messages.pending.push_back(OutboxMessage {
+            id,
+            event_id: new_id(),
+            event: e.clone(),
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R> HandlingEventRepository for InmemOutbox<R>
+where
+    R: HandlingEventRepository,
+{
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error> {
+        let _guard = self.append_lock.lock().await;
+        self.handling_events.append(e).await?;
+        self.record(e)
+    }
+
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error> {
+        let _guard = self.append_lock.lock().await;
+        self.handling_events.append_if_version(e, expected).await?;
+        self.record(e)
+    }
+
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error> {
+        self.handling_events.query_handling_history(id).await
+    }
+
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error> {
+        self.handling_events
+            .find_handling_events(criteria, page)
+            .await
+    }
+}
+
+#[async_trait]
+impl<R> HandlingEventOutbox for InmemOutbox<R>
+where
+    R: Send + Sync,
+{
+    async fn pending(&self, limit: usize) -> Result<Vec<OutboxMessage>, Error> {
+        Ok(self.lock()?.pending.iter().take(limit).cloned().collect())
+    }
+
+    async fn mark_sent(&self, id: u64) -> Result<(), Error> {
+        let mut messages = self.lock()?;
+        while messages.pending.front().is_some_and(|m| m.id <= id) {
+            messages.pending.pop_front();
+        }
+        Ok(())
+    }
+}
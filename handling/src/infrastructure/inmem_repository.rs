@@ -1,45 +1,311 @@
-use crate::domain::Repository;
+use crate::domain::handling::{
+    Cargo, CargoCriteria, CargoRepository, HandlingEvent, HandlingEventCriteria,
+    HandlingEventPosition, HandlingEventRepository, HandlingHistory, TrackingID,
+};
+use crate::domain::{Page, PageRequest, Repository, Version, Versioned};
+use crate::infrastructure::handling_event_log::Projection;
 use crate::Error;
+use async_trait::async_trait;
 use std::clone::Clone;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::hash::Hash;
-use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, Mutex};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+/// The number of shards of a repository created with `new`.
+pub const DEFAULT_SHARDS: usize = 16;
+
+type Shard<K, V> = RwLock<HashMap<K, Versioned<V>>>;
+
+/// InmemRepository keeps versioned entities in maps sharded by key. Each
+/// shard has its own read-write lock, so lookups never wait for each other
+/// and writes only wait for operations on the same shard.
 #[derive(Clone)]
-pub struct InmemRepository<K, V>(Arc<Mutex<HashMap<K, V>>>);
+pub struct InmemRepository<K, V>(Arc<[Shard<K, V>]>);
 
 impl<K, V> InmemRepository<K, V> {
     pub fn new() -> Self {
-        InmemRepository(Arc::new(Mutex::new(HashMap::new())))
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// A single shard makes every write exclusive of all other operations.
+    pub fn with_shards(shards: usize) -> Self {
+        InmemRepository((0..shards.max(1)).map(|_| RwLock::default()).collect())
+    }
+
+    pub(crate) fn from_contents(contents: HashMap<K, Versioned<V>>) -> Self
+    where
+        K: Eq + Hash,
+    {
+        let repository = Self::new();
+        for (key, value) in contents {
+            let shard = repository.shard(&key);
+            // Nobody else holds the new repository, so the lock is free.
+            if let Ok(mut data) = repository.0[shard].write() {
+                data.insert(key, value);
+            }
+        }
+        repository
+    }
+
+    pub(crate) fn contents(&self) -> Result<HashMap<K, Versioned<V>>, Error>
+    where
+        K: Eq + Hash + Clone,
+        V: Clone,
+    {
+        let mut contents = HashMap::new();
+        for shard in 0..self.0.len() {
+            let data = self.read(shard)?;
+            contents.extend(data.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        Ok(contents)
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.0.len() as u64) as usize
+    }
+
+    // A panic while holding a lock poisons it; report that instead of
+    // propagating the panic to every later caller.
+    fn read(&self, shard: usize) -> Result<RwLockReadGuard<'_, HashMap<K, Versioned<V>>>, Error> {
+        self.0[shard].read().map_err(|_| poisoned())
+    }
+
+    fn write(&self, shard: usize) -> Result<RwLockWriteGuard<'_, HashMap<K, Versioned<V>>>, Error> {
+        self.0[shard].write().map_err(|_| poisoned())
+    }
+
+    // Collects the matching entities of all shards. Shards are read one by
+    // one, so the result may miss writes made meanwhile to shards already
+    // read.
+    fn collect<T, F>(&self, mut f: F) -> Result<Vec<T>, Error>
+    where
+        F: FnMut(&K, &Versioned<V>) -> Option<T>,
+    {
+        let mut res = Vec::new();
+        for shard in 0..self.0.len() {
+            let data = self.read(shard)?;
+            res.extend(data.iter().filter_map(|(k, v)| f(k, v)));
+        }
+        Ok(res)
+    }
+}
+
+fn poisoned() -> Error {
+    Error::RepositoryError("repository lock is poisoned".to_string())
+}
+
+impl<K, V> Default for InmemRepository<K, V> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[async_trait]
 impl<K, V> Repository<K, V> for InmemRepository<K, V>
 where
-    K: Eq + Hash + std::fmt::Display + Clone + Send,
-    V: Clone + Send,
+    K: Eq + Hash + std::fmt::Display + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
 {
-    fn store(&self, key: K, value: &V) -> Result<(), Error> {
-        let r = self.0.clone();
-        let mut data = r.lock().unwrap();
-        data.deref_mut().insert(key, value.clone());
+    async fn store(&self, key: K, value: &V) -> Result<(), Error> {
+        let mut data = self.write(self.shard(&key))?;
+        let version = data.get(&key).map_or(0, |v| v.version) + 1;
+        data.insert(
+            key,
+            Versioned {
+                value: value.clone(),
+                version,
+            },
+        );
         Ok(())
     }
 
-    fn find(&self, key: K) -> Result<V, Error> {
-        let r = self.0.clone();
-        let data = r.lock().unwrap();
-        match data.deref().get(&key) {
+    async fn find(&self, key: K) -> Result<V, Error> {
+        Ok(self.find_versioned(key).await?.value)
+    }
+
+    async fn find_versioned(&self, key: K) -> Result<Versioned<V>, Error> {
+        let data = self.read(self.shard(&key))?;
+        match data.get(&key) {
             Some(value) => Ok(value.clone()),
-            None => Err(Error::RepositoryError(key.to_string())),
+            None => Err(Error::NotFound(key.to_string())),
         }
     }
 
-    fn find_all(&self) -> Result<Vec<V>, Error> {
-        let r = self.0.clone();
-        let data = r.lock().unwrap();
-        let res = data.deref().values().map(|v| v.clone()).collect();
-        Ok(res)
+    async fn store_if_version(
+        &self,
+        key: K,
+        value: &V,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        let mut data = self.write(self.shard(&key))?;
+        if data.get(&key).map(|v| v.version) != expected {
+            return Err(Error::VersionConflict(key.to_string()));
+        }
+        let version = expected.unwrap_or(0) + 1;
+        data.insert(
+            key,
+            Versioned {
+                value: value.clone(),
+                version,
+            },
+        );
+        Ok(version)
+    }
+
+    async fn find_all(&self) -> Result<Vec<V>, Error> {
+        self.collect(|_, v| Some(v.value.clone()))
+    }
+
+    async fn exists(&self, key: K) -> Result<bool, Error> {
+        let data = self.read(self.shard(&key))?;
+        Ok(data.contains_key(&key))
+    }
+
+    async fn delete(&self, key: K) -> Result<(), Error> {
+        let mut data = self.write(self.shard(&key))?;
+        match data.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound(key.to_string())),
+        }
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<V>, Error> {
+        page.validate()?;
+        page_by_key(self, &page, |_| true)
+    }
+}
+
+#[async_trait]
+impl CargoRepository for InmemRepository<TrackingID, Cargo> {
+    async fn find_by(
+        &self,
+        criteria: CargoCriteria,
+        page: PageRequest,
+    ) -> Result<Page<Cargo>, Error> {
+        page.validate()?;
+        page_by_key(self, &page, |cargo| criteria.is_satisfied_by(cargo))
+    }
+}
+
+// Pages through the entities in the order of the string form of their keys,
+// which is the order SQL backends list text keys in.
+fn page_by_key<K, V, P>(
+    repository: &InmemRepository<K, V>,
+    page: &PageRequest,
+    predicate: P,
+) -> Result<Page<V>, Error>
+where
+    K: std::fmt::Display,
+    V: Clone,
+    P: Fn(&V) -> bool,
+{
+    let mut matching = repository.collect(|k, v| {
+        if !predicate(&v.value) {
+            return None;
+        }
+        let key = k.to_string();
+        match &page.cursor {
+            Some(cursor) if &key <= cursor => None,
+            _ => Some((key, v.value.clone())),
+        }
+    })?;
+    matching.sort_by(|a, b| a.0.cmp(&b.0));
+    matching.truncate(page.limit + 1);
+    Ok(Page::from_ordered(matching, page.limit))
+}
+
+#[async_trait]
+impl HandlingEventRepository for InmemRepository<TrackingID, HandlingHistory> {
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error> {
+        self.apply(e)
+    }
+
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error> {
+        self.append_checked(e, Some(expected))
+    }
+
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error> {
+        let data = self.read(self.shard(&id))?;
+        match data.get(&id) {
+            Some(history) => Ok(history.value.clone()),
+            None => Ok(HandlingHistory::new(id)),
+        }
+    }
+
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error> {
+        page.validate()?;
+        let after = match &page.cursor {
+            Some(cursor) => Some(HandlingEventPosition::from_cursor(cursor)?),
+            None => None,
+        };
+        let mut matching: Vec<(HandlingEventPosition, HandlingEvent)> = self
+            .collect(|_, history| {
+                let events = history
+                    .value
+                    .handling_events()
+                    .iter()
+                    .filter(|e| criteria.is_satisfied_by(e))
+                    .map(|e| (HandlingEventPosition::of(e), e))
+                    .filter(|(position, _)| after.as_ref().is_none_or(|after| position > after))
+                    .map(|(position, e)| (position, e.clone()))
+                    .collect::<Vec<_>>();
+                Some(events)
+            })?
+            .into_iter()
+            .flatten()
+            .collect();
+        matching.sort_by(|a, b| a.0.cmp(&b.0));
+        matching.truncate(page.limit + 1);
+        let entries = matching
+            .into_iter()
+            .map(|(position, e)| (position.to_cursor(), e))
+            .collect();
+        Ok(Page::from_ordered(entries, page.limit))
+    }
+}
+
+impl InmemRepository<TrackingID, HandlingHistory> {
+    fn append_checked(&self, e: &HandlingEvent, expected: Option<Version>) -> Result<(), Error> {
+        let mut data = self.write(self.shard(&e.tracking_id))?;
+        let version = data
+            .get(&e.tracking_id)
+            .map_or(0, |history| history.value.version());
+        if expected.is_some_and(|expected| expected != version) {
+            return Err(Error::VersionConflict(e.tracking_id.clone()));
+        }
+        match data.get_mut(&e.tracking_id) {
+            // A rejected event leaves the history as it was.
+            Some(entry) => {
+                entry.value.append(e.clone())?;
+                entry.version = entry.value.version();
+            }
+            // The history of a cargo is only created by its first event.
+            None => {
+                let mut history = HandlingHistory::new(e.tracking_id.clone());
+                history.append(e.clone())?;
+                let version = history.version();
+                data.insert(
+                    e.tracking_id.clone(),
+                    Versioned {
+                        value: history,
+                        version,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Projection for InmemRepository<TrackingID, HandlingHistory> {
+    fn apply(&self, e: &HandlingEvent) -> Result<(), Error> {
+        self.append_checked(e, None)
     }
 }
@@ -0,0 +1,134 @@
+use super::inmem_outbox::InmemOutbox;
+use super::inmem_repository::InmemRepository;
+use crate::domain::handling::{Cargo, HandlingHistory, OutboxMessage, TrackingID};
+use crate::domain::inbox::CargoInbox;
+use crate::domain::location::{Location, UNLocode};
+use crate::domain::parking::ParkingLot;
+use crate::domain::voyage::{Voyage, VoyageNumber};
+use crate::domain::Versioned;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// InmemDatabase groups the in-memory repositories of the service so that
+/// their contents can be saved to a snapshot file and restored from it.
+#[derive(Clone)]
+pub struct InmemDatabase {
+    pub cargos: InmemRepository<TrackingID, Cargo>,
+    pub voyages: InmemRepository<VoyageNumber, Voyage>,
+    pub locations: InmemRepository<UNLocode, Location>,
+    pub handling_events: InmemOutbox<InmemRepository<TrackingID, HandlingHistory>>,
+    pub inboxes: InmemRepository<TrackingID, CargoInbox>,
+    pub parking_lots: InmemRepository<TrackingID, ParkingLot>,
+}
+
+impl Default for InmemDatabase {
+    fn default() -> Self {
+        InmemDatabase {
+            cargos: InmemRepository::new(),
+            voyages: InmemRepository::new(),
+            locations: InmemRepository::new(),
+            handling_events: InmemOutbox::new(InmemRepository::new()),
+            inboxes: InmemRepository::new(),
+            parking_lots: InmemRepository::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    cargos: HashMap<TrackingID, Versioned<Cargo>>,
+    voyages: HashMap<VoyageNumber, Versioned<Voyage>>,
+    locations: HashMap<UNLocode, Versioned<Location>>,
+    handling_histories: HashMap<TrackingID, Versioned<HandlingHistory>>,
+    // Missing from snapshots taken before the outbox was introduced.
+    #[serde(default)]
+    outbox: Vec<OutboxMessage>,
+    #[serde(default)]
+    inboxes: HashMap<TrackingID, Versioned<CargoInbox>>,
+    #[serde(default)]
+    parking_lots: HashMap<TrackingID, Versioned<ParkingLot>>,
+}
+
+impl InmemDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restores the repositories from a JSON snapshot. A missing snapshot
+    /// gives empty repositories, as on the first start.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(snapshot_error(path, err)),
+        };
+        let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| snapshot_error(path, err))?;
+        Ok(InmemDatabase {
+            cargos: InmemRepository::from_contents(snapshot.cargos),
+            voyages: InmemRepository::from_contents(snapshot.voyages),
+            locations: InmemRepository::from_contents(snapshot.locations),
+            handling_events: InmemOutbox::with_pending(
+                InmemRepository::from_contents(snapshot.handling_histories),
+                snapshot.outbox,
+            ),
+            inboxes: InmemRepository::from_contents(snapshot.inboxes),
+            parking_lots: InmemRepository::from_contents(snapshot.parking_lots),
+        })
+    }
+
+    /// Writes the snapshot next to the previous one and renames it over it, so
+    /// a crash while saving leaves the previous snapshot intact. The handling
+    /// histories are copied together with the outbox, the other repositories
+    /// one at a time, so an update running concurrently may be caught in
+    /// some of them only.
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let (handling_histories, outbox) = self
+            .handling_events
+            .contents(|histories| histories.contents())
+            .await?;
+        let snapshot = Snapshot {
+            cargos: self.cargos.contents()?,
+            voyages: self.voyages.contents()?,
+            locations: self.locations.contents()?,
+            handling_histories,
+            outbox,
+            inboxes: self.inboxes.contents()?,
+            parking_lots: self.parking_lots.contents()?,
+        };
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || write(&snapshot, &path))
+            .await
+            .map_err(|err| Error::RepositoryError(err.to_string()))?
+    }
+}
+
+fn write(snapshot: &Snapshot, path: &Path) -> Result<(), Error> {
+    let tmp_path = tmp_path(path);
+    let write = || -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, snapshot)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    };
+    write().map_err(|err| {
+        let _ = fs::remove_file(&tmp_path);
+        snapshot_error(path, err)
+    })
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn snapshot_error<E: std::fmt::Display>(path: &Path, err: E) -> Error {
+    Error::RepositoryError(format!("snapshot {}: {}", path.display(), err))
+}
@@ -1,2 +1,12 @@
+pub mod eventbus;
+pub mod handling_event_log;
+pub mod inmem_eventbus;
+pub mod inmem_outbox;
 pub mod inmem_repository;
+pub mod inmem_snapshot;
+#[cfg(feature = "nats")]
+pub mod nats_eventbus;
+pub mod outbox_relay;
+#[cfg(feature = "amqp")]
 pub mod rabbitmq_eventbus;
+pub mod sqlite_repository;
@@ -0,0 +1,347 @@
+use crate::application::envelope::{Envelope, CONTENT_TYPE};
+use crate::application::integration_events::{
+    EventHandler, EventService, OrderedSpawner, SubscribeManager,
+};
+use crate::application::pb::{HandlingEvent as PbHandlingEvent, OrderingKey, TypeName};
+use crate::domain::handling::HandlingEvent;
+use crate::infrastructure::eventbus::{handler_func, EventBusConfig, HandlerFunc, OrderedTasks};
+use crate::Error;
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, AckPolicy},
+    stream, AckKind, Context,
+};
+use async_nats::HeaderMap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::StreamExt;
+use log::{error, info, warn};
+use prost::Message;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, RwLock};
+
+// One stream holds the integration events of all services, every message
+// type is a subject of it.
+const STREAM_NAME: &str = "shipping";
+const CONSUMER_NAME: &str = "handling";
+const DEAD_LETTER_SUBJECT: &str = "handling.dead-letter";
+// Prefixes the names of the CloudEvents attributes in the message headers.
+const ATTRIBUTE_PREFIX: &str = "ce-";
+const CONTENT_TYPE_HEADER: &str = "Content-Type";
+// The subject a dead-lettered message was published to.
+const SUBJECT_HEADER: &str = "Handling-Subject";
+const DELIVERIES_HEADER: &str = "Handling-Deliveries";
+// The reason of the last failed attempt.
+const ERROR_HEADER: &str = "Handling-Error";
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// The handlers of the subscribed message types by subject.
+type Handlers = Arc<RwLock<HashMap<String, HandlerFunc>>>;
+
+/// NatsEventBus publishes and consumes integration events through NATS
+/// JetStream. All subscriptions share one durable pull consumer of the
+/// stream, which the broker keeps across reconnections and restarts of the
+/// service, so the messages of a cargo are handled in order whatever their
+/// types. The client reconnects by itself when the connection is lost.
+pub struct NatsEventBus {
+    jetstream: Context,
+    config: EventBusConfig,
+    handlers: Handlers,
+    tasks: OrderedTasks,
+    consuming: bool,
+}
+
+impl NatsEventBus {
+    pub async fn new(url: &str, config: EventBusConfig) -> DynResult<Self> {
+        let client = async_nats::connect(url).await?;
+        let jetstream = jetstream::new(client);
+        add_subjects(&jetstream, &[PbHandlingEvent::name(), DEAD_LETTER_SUBJECT]).await?;
+        Ok(NatsEventBus {
+            jetstream,
+            config,
+            handlers: Arc::default(),
+            tasks: OrderedTasks::default(),
+            consuming: false,
+        })
+    }
+}
+
+// Creates the stream or adds the subjects it lacks. Other services add the
+// subjects of the messages they publish the same way.
+async fn add_subjects(jetstream: &Context, subjects: &[&str]) -> Result<stream::Stream, Error> {
+    let mut stream = jetstream
+        .get_or_create_stream(stream::Config {
+            name: STREAM_NAME.to_string(),
+            subjects: subjects.iter().map(|s| s.to_string()).collect(),
+            ..stream::Config::default()
+        })
+        .await
+        .map_err(nats_error)?;
+    let mut config = stream.info().await.map_err(nats_error)?.config.clone();
+    let missing: Vec<String> = subjects
+        .iter()
+        .filter(|subject| !config.subjects.iter().any(|s| s == *subject))
+        .map(|subject| subject.to_string())
+        .collect();
+    if !missing.is_empty() {
+        config.subjects.extend(missing);
+        jetstream.update_stream(&config).await.map_err(nats_error)?;
+    }
+    Ok(stream)
+}
+
+#[async_trait]
+impl EventService for NatsEventBus {
+    async fn cargo_was_handled(&self, e: HandlingEvent, id: String) -> Result<(), Error> {
+        info!("{:?} cargo {}", e.activity.r#type, e.tracking_id);
+        let pb_event: PbHandlingEvent = e.into();
+        let mut buf = vec![];
+        pb_event.encode(&mut buf)?;
+        publish(
+            &self.jetstream,
+            PbHandlingEvent::name(),
+            headers(&Envelope::new(&pb_event, id)),
+            buf,
+        )
+        .await
+    }
+}
+
+// Sets the envelope after the NATS binding of CloudEvents, as headers named
+// by the attributes.
+fn headers(envelope: &Envelope) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE_HEADER, CONTENT_TYPE);
+    for (name, value) in envelope.attributes() {
+        headers.insert(
+            format!("{}{}", ATTRIBUTE_PREFIX, name).as_str(),
+            value.as_str(),
+        );
+    }
+    headers
+}
+
+fn envelope(message: &jetstream::Message) -> Envelope {
+    Envelope::from_attributes(message.subject.as_str(), |name| {
+        message.headers.as_ref().and_then(|headers| {
+            headers
+                .get(format!("{}{}", ATTRIBUTE_PREFIX, name).as_str())
+                .map(|value| value.to_string())
+        })
+    })
+}
+
+// Publishes the message and waits for the stream to store it.
+async fn publish(
+    jetstream: &Context,
+    subject: &str,
+    headers: HeaderMap,
+    payload: Vec<u8>,
+) -> Result<(), Error> {
+    jetstream
+        .publish_with_headers(subject.to_string(), headers, Bytes::from(payload))
+        .await
+        .map_err(publish_error)?
+        .await
+        .map_err(publish_error)?;
+    Ok(())
+}
+
+fn publish_error<E: Display>(err: E) -> Error {
+    Error::PublishError(err.to_string())
+}
+
+fn nats_error<E: Display>(err: E) -> Error {
+    Error::NatsError(err.to_string())
+}
+
+// Returns when the consumer is deleted, as the client keeps the stream of
+// messages going across reconnections.
+//
+// Every message is handled in its own task, once the messages received
+// before it with the same ordering key are handled, whatever their subjects.
+async fn process(
+    mut messages: pull::Stream,
+    handlers: Handlers,
+    tasks: OrderedTasks,
+    jetstream: Context,
+    config: EventBusConfig,
+) {
+    while let Some(message) = messages.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                error!("error while receiving message: {}", err);
+                continue;
+            }
+        };
+        let decoded = match handlers.read() {
+            Ok(handlers) => match handlers.get(message.subject.as_str()) {
+                Some(func) => func(message.payload.to_vec(), envelope(&message)),
+                None => Err(Error::NatsError(format!(
+                    "no handler for subject {}",
+                    message.subject
+                ))),
+            },
+            Err(_) => Err(Error::NatsError("handlers lock is poisoned".to_string())),
+        };
+        let (key, handling) = match decoded {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                let reason = format!("error while decoding message: {}", err);
+                error!("{}", reason);
+                dead_letter(&jetstream, &config, &message, &reason).await;
+                continue;
+            }
+        };
+        let jetstream = jetstream.clone();
+        let config = config.clone();
+        tasks.spawn(key, async move {
+            match handling.await {
+                Ok(_) => {
+                    if let Err(err) = message.ack().await {
+                        error!("error while acking message: {}", err);
+                    }
+                }
+                Err(err) => {
+                    error!("error while handling event: {}", err);
+                    reject(&jetstream, &config, &message, &err.to_string()).await;
+                }
+            }
+        });
+    }
+    info!("End of message stream")
+}
+
+// Has the message redelivered after the retry delay or, once its retries are
+// exhausted, moves it to the dead-letter subject.
+async fn reject(
+    jetstream: &Context,
+    config: &EventBusConfig,
+    message: &jetstream::Message,
+    reason: &str,
+) {
+    let retries = deliveries(message).saturating_sub(1);
+    if retries < config.max_retries {
+        let nak = AckKind::Nak(Some(config.retry_delay));
+        if let Err(err) = message.ack_with(nak).await {
+            error!("error while rejecting message: {}", err);
+        }
+    } else {
+        dead_letter(jetstream, config, message, reason).await;
+    }
+}
+
+// Publishes a copy of the message with the reason of the failure to the
+// dead-letter subject and terminates its delivery. The message is redelivered
+// as is if it can not be moved.
+async fn dead_letter(
+    jetstream: &Context,
+    config: &EventBusConfig,
+    message: &jetstream::Message,
+    reason: &str,
+) {
+    let deliveries = deliveries(message);
+    warn!(
+        "Dead-lettering message {} after {} deliveries",
+        message.subject, deliveries
+    );
+    let headers = dead_letter_headers(
+        message.headers.as_ref(),
+        &message.subject,
+        deliveries,
+        reason,
+    );
+    let moved = publish(
+        jetstream,
+        DEAD_LETTER_SUBJECT,
+        headers,
+        message.payload.to_vec(),
+    )
+    .await;
+    let res = match moved {
+        Ok(()) => message.ack_with(AckKind::Term).await,
+        Err(err) => {
+            error!("error while rejecting message: {}", err);
+            message
+                .ack_with(AckKind::Nak(Some(config.retry_delay)))
+                .await
+        }
+    };
+    if let Err(err) = res {
+        error!("error while rejecting message: {}", err);
+    }
+}
+
+/// Returns the headers of the dead-lettered copy of a message: those of the
+/// message, with the subject it was published to, the number of deliveries
+/// and the reason of the last failed attempt added.
+pub fn dead_letter_headers(
+    headers: Option<&HeaderMap>,
+    subject: &str,
+    deliveries: u32,
+    reason: &str,
+) -> HeaderMap {
+    let mut headers = headers.cloned().unwrap_or_default();
+    headers.insert(SUBJECT_HEADER, subject);
+    headers.insert(DELIVERIES_HEADER, deliveries.to_string().as_str());
+    headers.insert(ERROR_HEADER, reason);
+    headers
+}
+
+fn deliveries(message: &jetstream::Message) -> u32 {
+    message
+        .info()
+        .map(|info| info.delivered as u32)
+        .unwrap_or(1)
+}
+
+#[async_trait]
+impl SubscribeManager for NatsEventBus {
+    // Adds the subject to the filter of the consumer, which the broker
+    // updates in place, and starts consuming on the first subscription.
+    async fn subscribe<E, EH>(&mut self, eh: EH) -> DynResult<()>
+    where
+        E: Message + TypeName + OrderingKey + Default + 'static,
+        EH: EventHandler<E> + 'static,
+    {
+        let stream = add_subjects(&self.jetstream, &[E::name()]).await?;
+        let subjects = {
+            let mut handlers = self
+                .handlers
+                .write()
+                .map_err(|_| Error::NatsError("handlers lock is poisoned".to_string()))?;
+            handlers.insert(E::name().to_string(), handler_func(eh));
+            let mut subjects: Vec<String> = handlers.keys().cloned().collect();
+            subjects.sort();
+            subjects
+        };
+        let consumer: jetstream::consumer::PullConsumer = stream
+            .create_consumer(pull::Config {
+                durable_name: Some(CONSUMER_NAME.to_string()),
+                filter_subjects: subjects,
+                ack_policy: AckPolicy::Explicit,
+                max_ack_pending: self.config.prefetch_count.into(),
+                ..pull::Config::default()
+            })
+            .await?;
+        if !self.consuming {
+            let messages = consumer.messages().await?;
+            tokio::spawn(process(
+                messages,
+                self.handlers.clone(),
+                self.tasks.clone(),
+                self.jetstream.clone(),
+                self.config.clone(),
+            ));
+            self.consuming = true;
+        }
+        Ok(())
+    }
+
+    fn ordered_spawner(&self) -> OrderedSpawner {
+        self.tasks.spawner()
+    }
+}
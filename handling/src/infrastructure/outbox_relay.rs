@@ -0,0 +1,80 @@
+use crate::application::integration_events::EventService;
+use crate::domain::handling::HandlingEventOutbox;
+use crate::Error;
+use log::error;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const BATCH_SIZE: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// OutboxRelay publishes the messages of a handling event outbox in the
+/// order they were recorded and marks them sent. A failed publication is
+/// retried with exponential backoff until it succeeds.
+pub struct OutboxRelay<O, P> {
+    outbox: O,
+    publisher: P,
+}
+
+impl<O, P> OutboxRelay<O, P>
+where
+    O: HandlingEventOutbox + 'static,
+    P: EventService + 'static,
+{
+    pub fn new(outbox: O, publisher: P) -> Self {
+        OutboxRelay { outbox, publisher }
+    }
+
+    /// Relays messages in the background until the runtime shuts down.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    async fn run(self) {
+        let mut retry_delay = MIN_RETRY_DELAY;
+        loop {
+            match self.relay_pending().await {
+                Ok(0) => tokio::time::sleep(POLL_INTERVAL).await,
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "Failed to relay handling events, retrying in {:?}: {}",
+                        retry_delay, err
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    continue;
+                }
+            }
+            retry_delay = MIN_RETRY_DELAY;
+        }
+    }
+
+    /// Publishes a batch of pending messages and returns how many were sent.
+    /// Publishing stops at the first failure, so that no message overtakes
+    /// an earlier one.
+    pub async fn relay_pending(&self) -> Result<usize, Error> {
+        let messages = self.outbox.pending(BATCH_SIZE).await?;
+        let mut sent = 0;
+        let mut last_sent = None;
+        let mut res = Ok(());
+        for message in messages {
+            if let Err(err) = self
+                .publisher
+                .cargo_was_handled(message.event, message.event_id)
+                .await
+            {
+                res = Err(err);
+                break;
+            }
+            sent += 1;
+            last_sent = Some(message.id);
+        }
+        if let Some(id) = last_sent {
+            self.outbox.mark_sent(id).await?;
+        }
+        res.map(|_| sent)
+    }
+}
@@ -1,46 +1,91 @@
-use crate::application::integration_events::{EventHandler, EventService};
-use crate::application::pb::{HandlingEvent as PbHandlingEvent, TypeName};
+use crate::application::envelope::{Envelope, CONTENT_TYPE};
+use crate::application::integration_events::{
+    EventHandler, EventService, OrderedSpawner, SubscribeManager,
+};
+use crate::application::pb::{HandlingEvent as PbHandlingEvent, OrderingKey, TypeName};
 use crate::domain::handling::HandlingEvent;
+use crate::infrastructure::eventbus::{
+    handler_func, EventBusConfig, HandlerFunc, Handling, OrderedTasks,
+};
 use crate::Error;
 use async_trait::async_trait;
-use bytes::Bytes;
 use futures_util::stream::StreamExt;
 use lapin::{
-    options::*, types::FieldTable, BasicProperties, Channel, Connection, ConnectionProperties,
-    Consumer, ExchangeKind,
+    message::Delivery,
+    options::*,
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldArray, FieldTable},
+    BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use prost::Message;
 use std::collections::HashMap;
 use std::convert::From;
-use std::future::Future;
-use std::ops::Deref;
-use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
 
-const EXCHANGE_NAME: &'static str = "shipping";
-const QUEUE_NAME: &'static str = "handling.queue";
+const EXCHANGE_NAME: &str = "shipping";
+const QUEUE_NAME: &str = "handling.queue";
+// Failed messages wait in the retry queue until they expire, and are then
+// dead-lettered back to the handling queue.
+const RETRY_QUEUE_NAME: &str = "handling.retry";
+const DEAD_LETTER_EXCHANGE_NAME: &str = "handling.dead-letter";
+const DEAD_LETTER_QUEUE_NAME: &str = "handling.dead-letter";
+const RETRY_COUNT_HEADER: &str = "x-retry-count";
+// The reasons of all failed attempts, oldest first.
+const ERRORS_HEADER: &str = "x-errors";
+// Makes the broker write messages to disk, so they survive its restart.
+const PERSISTENT: u8 = 2;
+// Prefixes the names of the CloudEvents attributes in the message headers.
+const ATTRIBUTE_PREFIX: &str = "cloudEvents:";
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
 
-#[async_trait]
-pub trait SubscribeManager {
-    async fn subscribe<E, EH>(&mut self, eh: EH) -> DynResult<()>
-    where
-        E: Message + TypeName + Default + 'static,
-        EH: EventHandler<E> + Send + Sync + 'static;
-}
-
+/// EventBus publishes and consumes integration events through RabbitMQ.
+/// When the connection or the channel is lost, it reconnects in the
+/// background, declares the exchange and the queue again and rebinds the
+/// queue for every subscription.
 pub struct EventBus {
-    channel: Channel,
+    link: Arc<RwLock<Link>>,
     crt: ConsumerRT,
 }
 
 impl EventBus {
-    pub async fn new(url: &str) -> DynResult<Self> {
+    pub async fn new(url: &str, config: EventBusConfig) -> DynResult<Self> {
+        let link = Link::open(url, &config).await?;
+        let consumer = link.consume().await?;
+        let link = Arc::new(RwLock::new(link));
+        let crt = ConsumerRT::new(config);
+        tokio::spawn(supervise(
+            url.to_string(),
+            link.clone(),
+            crt.clone(),
+            consumer,
+        ));
+        Ok(EventBus { link, crt })
+    }
+}
+
+// Link is a connection to the broker and the channel the bus uses on it.
+struct Link {
+    conn: Connection,
+    channel: Channel,
+}
+
+impl Link {
+    async fn open(url: &str, config: &EventBusConfig) -> Result<Self, Error> {
         let conn = Connection::connect(url, ConnectionProperties::default()).await?;
         let channel = conn.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        channel
+            .basic_qos(config.prefetch_count, BasicQosOptions::default())
+            .await?;
         channel
             .exchange_declare(
                 EXCHANGE_NAME,
@@ -52,14 +97,67 @@ impl EventBus {
                 FieldTable::default(),
             )
             .await?;
+        let durable = QueueDeclareOptions {
+            durable: true,
+            ..QueueDeclareOptions::default()
+        };
+        channel
+            .queue_declare(QUEUE_NAME, durable, FieldTable::default())
+            .await?;
+        let mut retry_args = FieldTable::default();
+        retry_args.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        retry_args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(QUEUE_NAME.into()),
+        );
+        channel
+            .queue_declare(RETRY_QUEUE_NAME, durable, retry_args)
+            .await?;
+        channel
+            .exchange_declare(
+                DEAD_LETTER_EXCHANGE_NAME,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
         channel
-            .queue_declare(
+            .queue_declare(DEAD_LETTER_QUEUE_NAME, durable, FieldTable::default())
+            .await?;
+        channel
+            .queue_bind(
+                DEAD_LETTER_QUEUE_NAME,
+                DEAD_LETTER_EXCHANGE_NAME,
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        Ok(Link { conn, channel })
+    }
+
+    async fn bind(&self, msg_type: &str) -> Result<(), Error> {
+        self.channel
+            .queue_bind(
                 QUEUE_NAME,
-                QueueDeclareOptions::default(),
+                EXCHANGE_NAME,
+                msg_type,
+                QueueBindOptions::default(),
                 FieldTable::default(),
             )
             .await?;
-        let consumer = channel
+        Ok(())
+    }
+
+    async fn consume(&self) -> Result<Consumer, Error> {
+        let consumer = self
+            .channel
             .basic_consume(
                 QUEUE_NAME,
                 "",
@@ -67,45 +165,203 @@ impl EventBus {
                 FieldTable::default(),
             )
             .await?;
-        let crt = ConsumerRT::new();
-        crt.process(consumer).await;
+        Ok(consumer)
+    }
+
+    async fn close(&self) {
+        // Fails if the connection is already lost, which is why it is closed.
+        if let Err(err) = self.conn.close(0, "Reconnecting").await {
+            info!("Closing a lost RabbitMQ connection: {}", err);
+        }
+    }
+}
 
-        Ok(EventBus { channel, crt })
+// Consumes messages until the link is lost, then replaces it, for as long as
+// the service runs.
+async fn supervise(url: String, link: Arc<RwLock<Link>>, crt: ConsumerRT, mut consumer: Consumer) {
+    loop {
+        let channel = link.read().await.channel.clone();
+        crt.process(consumer, &channel).await;
+        warn!("Lost the connection to RabbitMQ, reconnecting");
+        consumer = reconnect(&url, &link, &crt).await;
+        info!("Reconnected to RabbitMQ");
+    }
+}
+
+async fn reconnect(url: &str, link: &RwLock<Link>, crt: &ConsumerRT) -> Consumer {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        match resubscribe(url, link, crt).await {
+            Ok(consumer) => return consumer,
+            Err(err) => {
+                error!(
+                    "Failed to reconnect to RabbitMQ, retrying in {:?}: {}",
+                    delay, err
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+async fn resubscribe(url: &str, link: &RwLock<Link>, crt: &ConsumerRT) -> Result<Consumer, Error> {
+    let new_link = Link::open(url, &crt.config).await?;
+    // Subscriptions made while the queue is rebound wait for the new link.
+    let mut link = link.write().await;
+    let subscribed = async {
+        for msg_type in crt.message_types().await {
+            new_link.bind(&msg_type).await?;
+        }
+        new_link.consume().await
+    }
+    .await;
+    match subscribed {
+        Ok(consumer) => {
+            let lost = std::mem::replace(&mut *link, new_link);
+            lost.close().await;
+            Ok(consumer)
+        }
+        Err(err) => {
+            new_link.close().await;
+            Err(err)
+        }
     }
 }
 
 #[async_trait]
 impl EventService for EventBus {
-    async fn cargo_was_handled(&self, e: HandlingEvent) -> Result<(), Error> {
+    async fn cargo_was_handled(&self, e: HandlingEvent, id: String) -> Result<(), Error> {
         info!("{:?} cargo {}", e.activity.r#type, e.tracking_id);
         let pb_event: PbHandlingEvent = e.into();
         let mut buf = vec![];
         pb_event.encode(&mut buf)?;
-        let _confitm = self
-            .channel
-            .basic_publish(
-                EXCHANGE_NAME,
-                PbHandlingEvent::name(),
-                BasicPublishOptions::default(),
-                buf,
-                BasicProperties::default().with_kind(PbHandlingEvent::name().into()),
-            )
-            .await?;
-        Ok(())
+        // Not held while waiting for the confirmation, which would hold up a
+        // reconnection.
+        let channel = self.link.read().await.channel.clone();
+        publish(
+            &channel,
+            EXCHANGE_NAME,
+            PbHandlingEvent::name(),
+            buf,
+            message_properties(&Envelope::new(&pb_event, id)),
+        )
+        .await
+    }
+}
+
+// Sets the envelope after the AMQP binding of CloudEvents, as headers named
+// by the attributes. Those AMQP has properties for are set there as well, for
+// consumers that do not know CloudEvents.
+fn message_properties(envelope: &Envelope) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    for (name, value) in envelope.attributes() {
+        headers.insert(
+            format!("{}{}", ATTRIBUTE_PREFIX, name).into(),
+            AMQPValue::LongString(value.into()),
+        );
+    }
+    let mut properties = BasicProperties::default()
+        .with_kind(envelope.r#type.as_str().into())
+        .with_content_type(CONTENT_TYPE.into())
+        .with_delivery_mode(PERSISTENT)
+        .with_headers(headers);
+    if let Some(id) = &envelope.id {
+        properties = properties.with_message_id(id.as_str().into());
+    }
+    if let Some(correlation_id) = &envelope.correlation_id {
+        properties = properties.with_correlation_id(correlation_id.as_str().into());
+    }
+    if let Some(time) = envelope.time {
+        properties = properties.with_timestamp(time.timestamp() as u64);
+    }
+    properties
+}
+
+// Reads the envelope of a message of type `kind`, falling back to the AMQP
+// properties for the attributes it has no headers for.
+fn envelope(properties: &BasicProperties, kind: &str) -> Envelope {
+    let headers = properties.headers().as_ref();
+    Envelope::from_attributes(kind, |name| {
+        let header = headers.and_then(|headers| {
+            headers
+                .inner()
+                .get(format!("{}{}", ATTRIBUTE_PREFIX, name).as_str())
+        });
+        match (header, name) {
+            (Some(AMQPValue::LongString(value)), _) => Some(value.to_string()),
+            (_, "id") => properties.message_id().as_ref().map(|id| id.to_string()),
+            (_, "correlationid") => properties
+                .correlation_id()
+                .as_ref()
+                .map(|id| id.to_string()),
+            _ => None,
+        }
+    })
+}
+
+// Publishes the message and waits for the broker to confirm it. Mandatory
+// makes the broker return a message no queue is bound for instead of
+// dropping it.
+async fn publish(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    payload: Vec<u8>,
+    properties: BasicProperties,
+) -> Result<(), Error> {
+    let confirm = channel
+        .basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions {
+                mandatory: true,
+                ..BasicPublishOptions::default()
+            },
+            payload,
+            properties,
+        )
+        .await?;
+    match tokio::time::timeout(CONFIRM_TIMEOUT, confirm).await {
+        Ok(confirmation) => confirmed(confirmation?),
+        Err(_) => Err(Error::PublishError(format!(
+            "no confirmation within {:?}",
+            CONFIRM_TIMEOUT
+        ))),
     }
 }
 
-type HandlerFunc =
-    Box<dyn Fn(Vec<u8>) -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> + Send + Sync>;
+fn confirmed(confirmation: Confirmation) -> Result<(), Error> {
+    match confirmation {
+        Confirmation::Ack(None) => Ok(()),
+        Confirmation::Ack(Some(returned)) => Err(Error::PublishError(format!(
+            "message returned: {} {}",
+            returned.reply_code, returned.reply_text
+        ))),
+        Confirmation::Nack(_) => Err(Error::PublishError("message nacked".to_string())),
+        Confirmation::NotRequested => Err(Error::PublishError(
+            "channel is not in confirm mode".to_string(),
+        )),
+    }
+}
 
+#[derive(Clone)]
 struct ConsumerRT {
     handlers: Arc<Mutex<HashMap<String, HandlerFunc>>>,
+    // Shared by the consumers of all connections, so the messages of a new
+    // one wait for those still handled from the previous one.
+    tasks: OrderedTasks,
+    config: EventBusConfig,
 }
 
 impl ConsumerRT {
-    fn new() -> Self {
+    fn new(config: EventBusConfig) -> Self {
         let handlers = Arc::new(Mutex::new(HashMap::new()));
-        ConsumerRT { handlers }
+        ConsumerRT {
+            handlers,
+            tasks: OrderedTasks::default(),
+            config,
+        }
     }
 
     async fn add_handler_func(&mut self, msg_type: String, func: HandlerFunc) {
@@ -113,87 +369,159 @@ impl ConsumerRT {
         data.insert(msg_type, func);
     }
 
-    async fn process(&self, mut consumer: Consumer) {
-        let handlers = self.handlers.clone();
-        tokio::spawn(async move {
-            while let Some(delivery) = consumer.next().await {
-                match delivery {
-                    Ok((_, delivery)) => {
-                        if let Some(dtype) = delivery.properties.kind() {
-                            let data = handlers.lock().await;
-                            match data.deref().get(dtype.as_str()) {
-                                Some(func) => match func(delivery.data.clone()).await {
-                                    Ok(_) => delivery
-                                        .ack(BasicAckOptions::default())
-                                        .await
-                                        .expect("RabbitMQ ack error"),
-                                    Err(err) => {
-                                        error!("error while handling event: {}", err);
-                                        delivery
-                                            .acker
-                                            .nack(BasicNackOptions::default())
-                                            .await
-                                            .expect("RabbitMQ nack error");
-                                    }
-                                },
-                                None => error!("No registered handler for: {}", dtype),
-                            }
-                        } else {
-                            error!("Could not get message type {}", delivery.delivery_tag);
+    async fn message_types(&self) -> Vec<String> {
+        self.handlers.lock().await.keys().cloned().collect()
+    }
+
+    // Returns when the consumer is canceled or fails, which happens when its
+    // channel or connection is lost.
+    //
+    // Every message is handled in its own task, once the messages received
+    // before it with the same ordering key are handled.
+    async fn process(&self, mut consumer: Consumer, channel: &Channel) {
+        while let Some(delivery) = consumer.next().await {
+            let delivery = match delivery {
+                Ok((_, delivery)) => delivery,
+                Err(err) => {
+                    error!("error while receiving message: {}", err);
+                    break;
+                }
+            };
+            let (key, handling) = match self.decode(&delivery).await {
+                Ok(decoded) => decoded,
+                Err(reason) => {
+                    error!("{}", reason);
+                    self.reject(channel, &delivery, &reason, false).await;
+                    continue;
+                }
+            };
+            let crt = self.clone();
+            let channel = channel.clone();
+            self.tasks.spawn(key, async move {
+                match handling.await {
+                    Ok(_) => {
+                        if let Err(err) = delivery.ack(BasicAckOptions::default()).await {
+                            error!("error while acking message: {}", Error::from(err));
                         }
                     }
-                    Err(err) => error!("error while receiving message: {}", err),
-                };
+                    Err(err) => {
+                        error!("error while handling event: {}", err);
+                        crt.reject(&channel, &delivery, &err.to_string(), true)
+                            .await;
+                    }
+                }
+            });
+        }
+        info!("End of message stream")
+    }
+
+    // Fails with the reason if the message can never be handled.
+    async fn decode(&self, delivery: &Delivery) -> Result<(String, Handling), String> {
+        let dtype = match delivery.properties.kind() {
+            Some(dtype) => dtype,
+            None => {
+                return Err(format!(
+                    "Could not get message type {}",
+                    delivery.delivery_tag
+                ))
             }
-            info!("End of message stream")
-        });
+        };
+        let handlers = self.handlers.lock().await;
+        match handlers.get(dtype.as_str()) {
+            Some(func) => func(
+                delivery.data.clone(),
+                envelope(&delivery.properties, dtype.as_str()),
+            )
+            .map_err(|err| format!("error while decoding message: {}", err)),
+            None => Err(format!("No registered handler for: {}", dtype)),
+        }
+    }
+
+    // Records the failure in the headers of the message and moves it either
+    // to the retry queue or, once its retries are exhausted or retrying is
+    // pointless, to the dead-letter queue. The message is requeued as is if
+    // it can not be moved.
+    async fn reject(&self, channel: &Channel, delivery: &Delivery, reason: &str, retry: bool) {
+        let retries = retry_count(&delivery.properties);
+        let moved = if retry && retries < self.config.max_retries {
+            let properties = failed(&delivery.properties, retries + 1, reason)
+                .with_expiration(self.config.retry_delay.as_millis().to_string().into());
+            publish(
+                channel,
+                "",
+                RETRY_QUEUE_NAME,
+                delivery.data.clone(),
+                properties,
+            )
+            .await
+        } else {
+            warn!(
+                "Dead-lettering message {} after {} retries",
+                delivery.delivery_tag, retries
+            );
+            let properties = failed(&delivery.properties, retries, reason);
+            publish(
+                channel,
+                DEAD_LETTER_EXCHANGE_NAME,
+                "",
+                delivery.data.clone(),
+                properties,
+            )
+            .await
+        };
+        let res = match moved {
+            Ok(()) => delivery.ack(BasicAckOptions::default()).await,
+            Err(err) => {
+                error!("error while rejecting message: {}", err);
+                delivery.acker.nack(BasicNackOptions::default()).await
+            }
+        };
+        if let Err(err) = res {
+            error!("error while rejecting message: {}", Error::from(err));
+        }
     }
 }
 
+fn retry_count(properties: &BasicProperties) -> u32 {
+    let count = properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(RETRY_COUNT_HEADER));
+    match count {
+        Some(AMQPValue::LongUInt(count)) => *count,
+        _ => 0,
+    }
+}
+
+fn failed(properties: &BasicProperties, retries: u32, reason: &str) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    let mut errors = match headers.inner().get(ERRORS_HEADER) {
+        Some(AMQPValue::FieldArray(errors)) => errors.clone(),
+        _ => FieldArray::default(),
+    };
+    errors.push(AMQPValue::LongString(reason.into()));
+    headers.insert(ERRORS_HEADER.into(), AMQPValue::FieldArray(errors));
+    headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(retries));
+    properties.clone().with_headers(headers)
+}
+
 #[async_trait]
 impl SubscribeManager for EventBus {
     async fn subscribe<E, EH>(&mut self, eh: EH) -> DynResult<()>
     where
-        E: Message + TypeName + Default + 'static,
-        EH: EventHandler<E> + Send + Sync + 'static,
+        E: Message + TypeName + OrderingKey + Default + 'static,
+        EH: EventHandler<E> + 'static,
     {
-        self.channel
-            .queue_bind(
-                QUEUE_NAME,
-                EXCHANGE_NAME,
-                E::name(),
-                QueueBindOptions::default(),
-                FieldTable::default(),
-            )
-            .await?;
-        let eh = Arc::new(Mutex::new(eh));
         self.crt
-            .add_handler_func(
-                E::name().to_string(),
-                Box::new(move |data: Vec<u8>| {
-                    let eh = eh.clone();
-                    Box::pin(async move {
-                        let e: std::result::Result<E, _> = Message::decode(Bytes::from(data));
-                        match e {
-                            Ok(e) => {
-                                let data = eh.lock().await;
-                                match data.deref().handle(e) {
-                                    Ok(_) => Ok(()),
-                                    Err(err) => {
-                                        error!("error while handling event: {}", err);
-                                        Err(err)
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                error!("error while decoding message: {}", err);
-                                Err(err.into())
-                            }
-                        }
-                    })
-                }),
-            )
+            .add_handler_func(E::name().to_string(), handler_func(eh))
             .await;
+        // The handler is registered first, so a reconnection running
+        // meanwhile binds the queue for it if this binding is lost.
+        self.link.read().await.bind(E::name()).await?;
         Ok(())
     }
+
+    fn ordered_spawner(&self) -> OrderedSpawner {
+        self.crt.tasks.spawner()
+    }
 }
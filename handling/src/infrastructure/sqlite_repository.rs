@@ -0,0 +1,1038 @@
+use crate::application::envelope::new_id;
+use crate::domain::handling::{
+    Cargo, CargoCriteria, CargoRepository, HandlingActivity, HandlingEvent, HandlingEventCriteria,
+    HandlingEventOutbox, HandlingEventPosition, HandlingEventRepository, HandlingEventType,
+    HandlingHistory, OutboxMessage, TrackingID,
+};
+use crate::domain::inbox::CargoInbox;
+use crate::domain::itinerary::{Itinerary, Leg};
+use crate::domain::location::{Location, UNLocode};
+use crate::domain::parking::ParkingLot;
+use crate::domain::voyage::{Voyage, VoyageNumber};
+use crate::domain::{Page, PageRequest, Repository, Version, Versioned};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction, NO_PARAMS};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Schema migrations. The number of applied migrations is kept in the
+// `user_version` pragma, so only append to this list.
+//
+// Timestamps are stored as RFC 3339 text in UTC, which sorts in time order,
+// so they are compared and ordered as text.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE cargos (
+    tracking_id      TEXT PRIMARY KEY,
+    origin           TEXT NOT NULL,
+    destination      TEXT NOT NULL,
+    arrival_deadline TEXT NOT NULL
+);
+CREATE TABLE legs (
+    tracking_id     TEXT    NOT NULL REFERENCES cargos (tracking_id) ON DELETE CASCADE,
+    position        INTEGER NOT NULL,
+    voyage_number   TEXT    NOT NULL,
+    load_location   TEXT    NOT NULL,
+    unload_location TEXT    NOT NULL,
+    load_time       TEXT    NOT NULL,
+    unload_time     TEXT    NOT NULL,
+    PRIMARY KEY (tracking_id, position)
+);
+CREATE TABLE voyages (
+    voyage_number TEXT PRIMARY KEY
+);
+CREATE TABLE locations (
+    un_locode TEXT PRIMARY KEY,
+    name      TEXT NOT NULL
+);
+CREATE TABLE handling_events (
+    id                INTEGER PRIMARY KEY AUTOINCREMENT,
+    tracking_id       TEXT    NOT NULL,
+    type              INTEGER NOT NULL,
+    location          TEXT    NOT NULL,
+    voyage_number     TEXT    NOT NULL,
+    registration_time TEXT    NOT NULL,
+    completion_time   TEXT    NOT NULL,
+    is_expected       INTEGER NOT NULL
+);
+CREATE INDEX handling_events_tracking_id ON handling_events (tracking_id);
+"#,
+    r#"
+CREATE INDEX cargos_destination ON cargos (destination);
+CREATE INDEX handling_events_completion_time
+    ON handling_events (completion_time, registration_time, tracking_id);
+"#,
+    r#"
+ALTER TABLE cargos ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE voyages ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE locations ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+"#,
+    r#"
+CREATE TABLE outbox (
+    id                INTEGER PRIMARY KEY AUTOINCREMENT,
+    handling_event_id INTEGER NOT NULL REFERENCES handling_events (id),
+    event_id          TEXT    NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE inboxes (
+    id       TEXT    PRIMARY KEY,
+    document TEXT    NOT NULL,
+    version  INTEGER NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE parking_lots (
+    id       TEXT    PRIMARY KEY,
+    document TEXT    NOT NULL,
+    version  INTEGER NOT NULL
+);
+"#,
+];
+
+/// SqliteDatabase is a connection to a SQLite database shared by all the
+/// repositories created from it.
+#[derive(Clone)]
+pub struct SqliteDatabase(Arc<Mutex<Connection>>);
+
+impl SqliteDatabase {
+    /// Opens the database and applies pending migrations. The URL is either a
+    /// file path, optionally prefixed by `sqlite://`, or `:memory:`.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        let path = url.strip_prefix("sqlite://").unwrap_or(url);
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        migrate(&mut conn)?;
+        Ok(SqliteDatabase(Arc::new(Mutex::new(conn))))
+    }
+
+    pub fn repository<K, V>(&self) -> SqliteRepository<K, V> {
+        SqliteRepository {
+            db: self.clone(),
+            _entity: PhantomData,
+        }
+    }
+
+    pub fn inboxes(&self) -> JsonDocumentRepository<TrackingID, CargoInbox> {
+        self.documents("inboxes")
+    }
+
+    pub fn parking_lots(&self) -> JsonDocumentRepository<TrackingID, ParkingLot> {
+        self.documents("parking_lots")
+    }
+
+    fn documents<K, V>(&self, table: &'static str) -> JsonDocumentRepository<K, V> {
+        JsonDocumentRepository {
+            db: self.clone(),
+            table,
+            _entity: PhantomData,
+        }
+    }
+
+    // SQLite calls block, so they are run on the blocking thread pool to keep
+    // the runtime threads free.
+    async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = db.lock()?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|err| Error::RepositoryError(err.to_string()))?
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>, Error> {
+        self.0
+            .lock()
+            .map_err(|_| Error::RepositoryError("database lock is poisoned".to_string()))
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", &(version as i64 + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// SqliteRepository stores entities of type `V` keyed by `K` in their own
+/// table of a `SqliteDatabase`.
+pub struct SqliteRepository<K, V> {
+    db: SqliteDatabase,
+    _entity: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for SqliteRepository<K, V> {
+    fn clone(&self) -> Self {
+        self.db.repository()
+    }
+}
+
+#[async_trait]
+impl Repository<TrackingID, Cargo> for SqliteRepository<TrackingID, Cargo> {
+    async fn store(&self, id: TrackingID, cargo: &Cargo) -> Result<(), Error> {
+        self.write(id, cargo, Expected::Any).await?;
+        Ok(())
+    }
+
+    async fn find(&self, id: TrackingID) -> Result<Cargo, Error> {
+        Ok(self.find_versioned(id).await?.value)
+    }
+
+    async fn find_versioned(&self, id: TrackingID) -> Result<Versioned<Cargo>, Error> {
+        self.db
+            .run(move |conn| {
+                let cargo = conn
+                    .query_row(
+                        "SELECT tracking_id, origin, destination, arrival_deadline, version
+                         FROM cargos WHERE tracking_id = ?1",
+                        params![id],
+                        |row| {
+                            Ok(Versioned {
+                                value: cargo_from_row(row)?,
+                                version: row.get::<_, i64>(4)? as Version,
+                            })
+                        },
+                    )
+                    .optional()?;
+                match cargo {
+                    Some(mut cargo) => {
+                        cargo.value.itinerary = find_itinerary(conn, &id)?;
+                        Ok(cargo)
+                    }
+                    None => Err(Error::NotFound(id)),
+                }
+            })
+            .await
+    }
+
+    async fn store_if_version(
+        &self,
+        id: TrackingID,
+        cargo: &Cargo,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        self.write(id, cargo, Expected::Version(expected)).await
+    }
+
+    async fn find_all(&self) -> Result<Vec<Cargo>, Error> {
+        self.db
+            .run(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT tracking_id, origin, destination, arrival_deadline
+                     FROM cargos ORDER BY tracking_id",
+                )?;
+                let cargos = stmt
+                    .query_map(NO_PARAMS, cargo_from_row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                cargos
+                    .into_iter()
+                    .map(|mut cargo| {
+                        cargo.itinerary = find_itinerary(conn, &cargo.tracking_id)?;
+                        Ok(cargo)
+                    })
+                    .collect()
+            })
+            .await
+    }
+
+    async fn exists(&self, id: TrackingID) -> Result<bool, Error> {
+        self.db
+            .run(move |conn| row_exists(conn, "cargos", "tracking_id", &id))
+            .await
+    }
+
+    async fn delete(&self, id: TrackingID) -> Result<(), Error> {
+        // Legs are deleted by the foreign key cascade.
+        self.db
+            .run(move |conn| delete_row(conn, "cargos", "tracking_id", id))
+            .await
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<Cargo>, Error> {
+        self.find_by(CargoCriteria::default(), page).await
+    }
+}
+
+impl SqliteRepository<TrackingID, Cargo> {
+    async fn write(
+        &self,
+        id: TrackingID,
+        cargo: &Cargo,
+        expected: Expected,
+    ) -> Result<Version, Error> {
+        let cargo = cargo.clone();
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let version = next_version(&tx, "cargos", "tracking_id", &id, expected)?;
+                tx.execute(
+                    "INSERT INTO cargos (tracking_id, origin, destination, arrival_deadline, version)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (tracking_id) DO UPDATE SET
+                        origin = excluded.origin,
+                        destination = excluded.destination,
+                        arrival_deadline = excluded.arrival_deadline,
+                        version = excluded.version",
+                    params![
+                        id,
+                        cargo.origin,
+                        cargo.destination,
+                        cargo.arrival_deadline,
+                        version as i64
+                    ],
+                )?;
+                tx.execute("DELETE FROM legs WHERE tracking_id = ?1", params![id])?;
+                for (position, leg) in cargo.itinerary.legs.iter().enumerate() {
+                    tx.execute(
+                        "INSERT INTO legs (tracking_id, position, voyage_number, load_location,
+                            unload_location, load_time, unload_time)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![
+                            id,
+                            position as i64,
+                            leg.voyage_number,
+                            leg.load_location,
+                            leg.unload_location,
+                            leg.load_time,
+                            leg.unload_time
+                        ],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(version)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl CargoRepository for SqliteRepository<TrackingID, Cargo> {
+    async fn find_by(
+        &self,
+        criteria: CargoCriteria,
+        page: PageRequest,
+    ) -> Result<Page<Cargo>, Error> {
+        page.validate()?;
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT tracking_id, origin, destination, arrival_deadline
+                     FROM cargos
+                     WHERE (?1 IS NULL OR origin = ?1)
+                       AND (?2 IS NULL OR destination = ?2)
+                       AND (?3 IS NULL OR tracking_id > ?3)
+                     ORDER BY tracking_id LIMIT ?4",
+                )?;
+                let cargos = stmt
+                    .query_map(
+                        params![
+                            criteria.origin,
+                            criteria.destination,
+                            page.cursor,
+                            page.limit as i64 + 1
+                        ],
+                        cargo_from_row,
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let entries = cargos
+                    .into_iter()
+                    .map(|mut cargo| {
+                        cargo.itinerary = find_itinerary(conn, &cargo.tracking_id)?;
+                        Ok((cargo.tracking_id.clone(), cargo))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                Ok(Page::from_ordered(entries, page.limit))
+            })
+            .await
+    }
+}
+
+fn cargo_from_row(row: &Row) -> rusqlite::Result<Cargo> {
+    Ok(Cargo {
+        tracking_id: row.get(0)?,
+        origin: row.get(1)?,
+        destination: row.get(2)?,
+        arrival_deadline: row.get(3)?,
+        itinerary: Itinerary::default(),
+    })
+}
+
+fn find_itinerary(conn: &Connection, id: &str) -> Result<Itinerary, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT voyage_number, load_location, unload_location, load_time, unload_time
+         FROM legs WHERE tracking_id = ?1 ORDER BY position",
+    )?;
+    let legs = stmt
+        .query_map(params![id], |row| {
+            Ok(Leg {
+                voyage_number: row.get(0)?,
+                load_location: row.get(1)?,
+                unload_location: row.get(2)?,
+                load_time: row.get(3)?,
+                unload_time: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Itinerary { legs })
+}
+
+// The version a write expects to replace: any for a plain store, the one
+// read by an optimistic update, or none for an entity to be created.
+#[derive(Clone, Copy)]
+enum Expected {
+    Any,
+    Version(Option<Version>),
+}
+
+// Table and column names passed to these helpers are constants of this
+// module, never user input.
+fn row_exists(conn: &Connection, table: &str, column: &str, id: &str) -> Result<bool, Error> {
+    let exists = conn.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE {} = ?1)",
+            table, column
+        ),
+        params![id],
+        |row| row.get(0),
+    )?;
+    Ok(exists)
+}
+
+// Checks the stored version of a row and returns the version to write.
+fn next_version(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    id: &str,
+    expected: Expected,
+) -> Result<Version, Error> {
+    let actual: Option<i64> = conn
+        .query_row(
+            &format!("SELECT version FROM {} WHERE {} = ?1", table, column),
+            params![id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let actual = actual.map(|version| version as Version);
+    match expected {
+        Expected::Version(expected) if expected != actual => {
+            Err(Error::VersionConflict(id.to_string()))
+        }
+        _ => Ok(actual.unwrap_or(0) + 1),
+    }
+}
+
+fn delete_row(conn: &Connection, table: &str, column: &str, id: String) -> Result<(), Error> {
+    let deleted = conn.execute(
+        &format!("DELETE FROM {} WHERE {} = ?1", table, column),
+        params![id],
+    )?;
+    if deleted == 0 {
+        return Err(Error::NotFound(id));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Repository<VoyageNumber, Voyage> for SqliteRepository<VoyageNumber, Voyage> {
+    async fn store(&self, voyage_number: VoyageNumber, voyage: &Voyage) -> Result<(), Error> {
+        self.write(voyage_number, voyage, Expected::Any).await?;
+        Ok(())
+    }
+
+    async fn find(&self, voyage_number: VoyageNumber) -> Result<Voyage, Error> {
+        Ok(self.find_versioned(voyage_number).await?.value)
+    }
+
+    async fn find_versioned(
+        &self,
+        voyage_number: VoyageNumber,
+    ) -> Result<Versioned<Voyage>, Error> {
+        self.db
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT version FROM voyages WHERE voyage_number = ?1",
+                    params![voyage_number],
+                    |row| {
+                        Ok(Versioned {
+                            value: Voyage {},
+                            version: row.get::<_, i64>(0)? as Version,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or(Error::NotFound(voyage_number))
+            })
+            .await
+    }
+
+    async fn store_if_version(
+        &self,
+        voyage_number: VoyageNumber,
+        voyage: &Voyage,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        self.write(voyage_number, voyage, Expected::Version(expected))
+            .await
+    }
+
+    async fn find_all(&self) -> Result<Vec<Voyage>, Error> {
+        self.db
+            .run(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT voyage_number FROM voyages ORDER BY voyage_number")?;
+                let voyages = stmt
+                    .query_map(NO_PARAMS, |_| Ok(Voyage {}))?
+                    .collect::<Result<_, _>>()?;
+                Ok(voyages)
+            })
+            .await
+    }
+
+    async fn exists(&self, voyage_number: VoyageNumber) -> Result<bool, Error> {
+        self.db
+            .run(move |conn| row_exists(conn, "voyages", "voyage_number", &voyage_number))
+            .await
+    }
+
+    async fn delete(&self, voyage_number: VoyageNumber) -> Result<(), Error> {
+        self.db
+            .run(move |conn| delete_row(conn, "voyages", "voyage_number", voyage_number))
+            .await
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<Voyage>, Error> {
+        page.validate()?;
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT voyage_number FROM voyages
+                     WHERE ?1 IS NULL OR voyage_number > ?1
+                     ORDER BY voyage_number LIMIT ?2",
+                )?;
+                let entries = stmt
+                    .query_map(params![page.cursor, page.limit as i64 + 1], |row| {
+                        Ok((row.get(0)?, Voyage {}))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(Page::from_ordered(entries, page.limit))
+            })
+            .await
+    }
+}
+
+impl SqliteRepository<VoyageNumber, Voyage> {
+    async fn write(
+        &self,
+        voyage_number: VoyageNumber,
+        _: &Voyage,
+        expected: Expected,
+    ) -> Result<Version, Error> {
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let version =
+                    next_version(&tx, "voyages", "voyage_number", &voyage_number, expected)?;
+                tx.execute(
+                    "INSERT INTO voyages (voyage_number, version) VALUES (?1, ?2)
+                     ON CONFLICT (voyage_number) DO UPDATE SET version = excluded.version",
+                    params![voyage_number, version as i64],
+                )?;
+                tx.commit()?;
+                Ok(version)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl Repository<UNLocode, Location> for SqliteRepository<UNLocode, Location> {
+    async fn store(&self, un_locode: UNLocode, location: &Location) -> Result<(), Error> {
+        self.write(un_locode, location, Expected::Any).await?;
+        Ok(())
+    }
+
+    async fn find(&self, un_locode: UNLocode) -> Result<Location, Error> {
+        Ok(self.find_versioned(un_locode).await?.value)
+    }
+
+    async fn find_versioned(&self, un_locode: UNLocode) -> Result<Versioned<Location>, Error> {
+        self.db
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT un_locode, name, version FROM locations WHERE un_locode = ?1",
+                    params![un_locode],
+                    |row| {
+                        Ok(Versioned {
+                            value: Location::new(row.get(0)?, row.get(1)?),
+                            version: row.get::<_, i64>(2)? as Version,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or(Error::NotFound(un_locode))
+            })
+            .await
+    }
+
+    async fn store_if_version(
+        &self,
+        un_locode: UNLocode,
+        location: &Location,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        self.write(un_locode, location, Expected::Version(expected))
+            .await
+    }
+
+    async fn find_all(&self) -> Result<Vec<Location>, Error> {
+        self.db
+            .run(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT un_locode, name FROM locations ORDER BY un_locode")?;
+                let locations = stmt
+                    .query_map(NO_PARAMS, |row| Ok(Location::new(row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                Ok(locations)
+            })
+            .await
+    }
+
+    async fn exists(&self, un_locode: UNLocode) -> Result<bool, Error> {
+        self.db
+            .run(move |conn| row_exists(conn, "locations", "un_locode", &un_locode))
+            .await
+    }
+
+    async fn delete(&self, un_locode: UNLocode) -> Result<(), Error> {
+        self.db
+            .run(move |conn| delete_row(conn, "locations", "un_locode", un_locode))
+            .await
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<Location>, Error> {
+        page.validate()?;
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT un_locode, name FROM locations
+                     WHERE ?1 IS NULL OR un_locode > ?1
+                     ORDER BY un_locode LIMIT ?2",
+                )?;
+                let entries = stmt
+                    .query_map(params![page.cursor, page.limit as i64 + 1], |row| {
+                        let un_locode: UNLocode = row.get(0)?;
+                        Ok((un_locode.clone(), Location::new(un_locode, row.get(1)?)))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(Page::from_ordered(entries, page.limit))
+            })
+            .await
+    }
+}
+
+impl SqliteRepository<UNLocode, Location> {
+    async fn write(
+        &self,
+        un_locode: UNLocode,
+        location: &Location,
+        expected: Expected,
+    ) -> Result<Version, Error> {
+        let name = location.name().to_string();
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let version = next_version(&tx, "locations", "un_locode", &un_locode, expected)?;
+                tx.execute(
+                    "INSERT INTO locations (un_locode, name, version) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (un_locode) DO UPDATE SET
+                        name = excluded.name,
+                        version = excluded.version",
+                    params![un_locode, name, version as i64],
+                )?;
+                tx.commit()?;
+                Ok(version)
+            })
+            .await
+    }
+}
+
+// Documents are stored as JSON text.
+fn to_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    serde_json::to_string(value).map_err(|err| Error::RepositoryError(err.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let json: String = row.get(idx)?;
+    serde_json::from_str(&json)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(err)))
+}
+
+/// JsonDocumentRepository stores entities of type `V` keyed by `K` as JSON
+/// documents in a table of a `SqliteDatabase`, for entities that are only
+/// ever looked up by their key.
+pub struct JsonDocumentRepository<K, V> {
+    db: SqliteDatabase,
+    // Created by a migration, with the columns id, document and version.
+    table: &'static str,
+    _entity: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for JsonDocumentRepository<K, V> {
+    fn clone(&self) -> Self {
+        self.db.documents(self.table)
+    }
+}
+
+#[async_trait]
+impl<K, V> Repository<K, V> for JsonDocumentRepository<K, V>
+where
+    K: Eq + Hash + Display + Clone + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    async fn store(&self, key: K, value: &V) -> Result<(), Error> {
+        self.write(key, value, Expected::Any).await?;
+        Ok(())
+    }
+
+    async fn find(&self, key: K) -> Result<V, Error> {
+        Ok(self.find_versioned(key).await?.value)
+    }
+
+    async fn find_versioned(&self, key: K) -> Result<Versioned<V>, Error> {
+        let table = self.table;
+        let id = key.to_string();
+        self.db
+            .run(move |conn| {
+                conn.query_row(
+                    &format!("SELECT document, version FROM {} WHERE id = ?1", table),
+                    params![id],
+                    |row| {
+                        Ok(Versioned {
+                            value: from_json(row, 0)?,
+                            version: row.get::<_, i64>(1)? as Version,
+                        })
+                    },
+                )
+                .optional()?
+                .ok_or(Error::NotFound(id))
+            })
+            .await
+    }
+
+    async fn store_if_version(
+        &self,
+        key: K,
+        value: &V,
+        expected: Option<Version>,
+    ) -> Result<Version, Error> {
+        self.write(key, value, Expected::Version(expected)).await
+    }
+
+    async fn find_all(&self) -> Result<Vec<V>, Error> {
+        let table = self.table;
+        self.db
+            .run(move |conn| {
+                let mut stmt =
+                    conn.prepare(&format!("SELECT document FROM {} ORDER BY id", table))?;
+                let values = stmt
+                    .query_map(NO_PARAMS, |row| from_json(row, 0))?
+                    .collect::<Result<_, _>>()?;
+                Ok(values)
+            })
+            .await
+    }
+
+    async fn exists(&self, key: K) -> Result<bool, Error> {
+        let table = self.table;
+        let id = key.to_string();
+        self.db
+            .run(move |conn| row_exists(conn, table, "id", &id))
+            .await
+    }
+
+    async fn delete(&self, key: K) -> Result<(), Error> {
+        let table = self.table;
+        let id = key.to_string();
+        self.db
+            .run(move |conn| delete_row(conn, table, "id", id))
+            .await
+    }
+
+    async fn find_page(&self, page: PageRequest) -> Result<Page<V>, Error> {
+        page.validate()?;
+        let table = self.table;
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT id, document FROM {}
+                     WHERE ?1 IS NULL OR id > ?1
+                     ORDER BY id LIMIT ?2",
+                    table
+                ))?;
+                let entries = stmt
+                    .query_map(params![page.cursor, page.limit as i64 + 1], |row| {
+                        Ok((row.get(0)?, from_json(row, 1)?))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(Page::from_ordered(entries, page.limit))
+            })
+            .await
+    }
+}
+
+impl<K: Display, V: Serialize> JsonDocumentRepository<K, V> {
+    async fn write(&self, key: K, value: &V, expected: Expected) -> Result<Version, Error> {
+        let table = self.table;
+        let id = key.to_string();
+        let document = to_json(value)?;
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                let version = next_version(&tx, table, "id", &id, expected)?;
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {} (id, document, version) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (id) DO UPDATE SET
+                            document = excluded.document,
+                            version = excluded.version",
+                        table
+                    ),
+                    params![id, document, version as i64],
+                )?;
+                tx.commit()?;
+                Ok(version)
+            })
+            .await
+    }
+}
+
+impl SqliteRepository<TrackingID, HandlingHistory> {
+    async fn append_checked(
+        &self,
+        e: &HandlingEvent,
+        expected: Option<Version>,
+    ) -> Result<(), Error> {
+        let e = e.clone();
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                // The history rejects events that break the cargo lifecycle.
+                let mut history = query_handling_history(&tx, &e.tracking_id)?;
+                if expected.is_some_and(|expected| expected != history.version()) {
+                    return Err(Error::VersionConflict(e.tracking_id));
+                }
+                history.append(e.clone())?;
+                tx.execute(
+                    "INSERT INTO handling_events (tracking_id, type, location, voyage_number,
+                        registration_time, completion_time, is_expected)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        e.tracking_id,
+                        i32::from(e.activity.r#type.clone()),
+                        e.activity.location,
+                        e.activity.voyage_number,
+                        e.registration_time,
+                        e.completion_time,
+                        e.is_expected
+                    ],
+                )?;
+                // Committed with the event, so the event is published even if
+                // the service stops right after.
+                tx.execute(
+                    "INSERT INTO outbox (handling_event_id, event_id) VALUES (?1, ?2)",
+                    params![tx.last_insert_rowid(), new_id()],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl HandlingEventRepository for SqliteRepository<TrackingID, HandlingHistory> {
+    async fn append(&self, e: &HandlingEvent) -> Result<(), Error> {
+        self.append_checked(e, None).await
+    }
+
+    async fn append_if_version(&self, e: &HandlingEvent, expected: Version) -> Result<(), Error> {
+        self.append_checked(e, Some(expected)).await
+    }
+
+    async fn query_handling_history(&self, id: TrackingID) -> Result<HandlingHistory, Error> {
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                query_handling_history(&tx, &id)
+            })
+            .await
+    }
+
+    async fn find_handling_events(
+        &self,
+        criteria: HandlingEventCriteria,
+        page: PageRequest,
+    ) -> Result<Page<HandlingEvent>, Error> {
+        page.validate()?;
+        let after = match &page.cursor {
+            Some(cursor) => Some(HandlingEventPosition::from_cursor(cursor)?),
+            None => None,
+        };
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT tracking_id, type, location, voyage_number, registration_time,
+                        completion_time, is_expected
+                     FROM handling_events
+                     WHERE (?1 IS NULL OR location = ?1)
+                       AND (?2 IS NULL OR type = ?2)
+                       AND (?3 IS NULL OR completion_time >= ?3)
+                       AND (?4 IS NULL OR completion_time < ?4)
+                       AND (?5 IS NULL
+                            OR (completion_time, registration_time, tracking_id) > (?5, ?6, ?7))
+                     ORDER BY completion_time, registration_time, tracking_id LIMIT ?8",
+                )?;
+                let rows = stmt
+                    .query_map(
+                        params![
+                            criteria.location,
+                            criteria.event_type.map(i32::from),
+                            criteria.completed_from,
+                            criteria.completed_to,
+                            after.as_ref().map(|p| p.completion_time),
+                            after.as_ref().map(|p| p.registration_time),
+                            after.map(|p| p.tracking_id),
+                            page.limit as i64 + 1
+                        ],
+                        handling_event_row,
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let entries = rows
+                    .into_iter()
+                    .map(|row| {
+                        let e = handling_event_from_row(row)?;
+                        Ok((HandlingEventPosition::of(&e).to_cursor(), e))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                Ok(Page::from_ordered(entries, page.limit))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl HandlingEventOutbox for SqliteRepository<TrackingID, HandlingHistory> {
+    async fn pending(&self, limit: usize) -> Result<Vec<OutboxMessage>, Error> {
+        self.db
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT e.tracking_id, e.type, e.location, e.voyage_number,
+                        e.registration_time, e.completion_time, e.is_expected, o.id, o.event_id
+                     FROM outbox o JOIN handling_events e ON e.id = o.handling_event_id
+                     ORDER BY o.id LIMIT ?1",
+                )?;
+                let rows = stmt
+                    .query_map(params![limit as i64], |row| {
+                        Ok((
+                            handling_event_row(row)?,
+                            row.get::<_, i64>(7)?,
+                            row.get::<_, String>(8)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                rows.into_iter()
+                    .map(|(row, id, event_id)| {
+                        Ok(OutboxMessage {
+                            id: id as u64,
+                            event_id,
+                            event: handling_event_from_row(row)?,
+                        })
+                    })
+                    .collect()
+            })
+            .await
+    }
+
+    async fn mark_sent(&self, id: u64) -> Result<(), Error> {
+        self.db
+            .run(move |conn| {
+                conn.execute("DELETE FROM outbox WHERE id <= ?1", params![id as i64])?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+type HandlingEventRow = (
+    TrackingID,
+    i32,
+    UNLocode,
+    VoyageNumber,
+    DateTime<Utc>,
+    DateTime<Utc>,
+    bool,
+);
+
+// The event type is converted outside of the row mapping as its errors are
+// not SQLite errors.
+fn handling_event_row(row: &Row) -> rusqlite::Result<HandlingEventRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+    ))
+}
+
+fn handling_event_from_row(row: HandlingEventRow) -> Result<HandlingEvent, Error> {
+    let (tracking_id, r#type, location, voyage_number, registered, completed, expected) = row;
+    Ok(HandlingEvent {
+        tracking_id,
+        activity: HandlingActivity {
+            r#type: HandlingEventType::try_from(r#type)?,
+            location,
+            voyage_number,
+        },
+        registration_time: registered,
+        completion_time: completed,
+        is_expected: expected,
+    })
+}
+
+fn query_handling_history(tx: &Transaction, id: &str) -> Result<HandlingHistory, Error> {
+    let mut stmt = tx.prepare(
+        "SELECT tracking_id, type, location, voyage_number, registration_time,
+            completion_time, is_expected
+         FROM handling_events WHERE tracking_id = ?1 ORDER BY id",
+    )?;
+    let rows = stmt
+        .query_map(params![id], handling_event_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut history = HandlingHistory::new(id.to_string());
+    for row in rows {
+        history.append(handling_event_from_row(row)?)?;
+    }
+    Ok(history)
+}
@@ -0,0 +1,85 @@
+// Every test binary compiles its own copy of this module and uses only part
+// of it.
+#![allow(dead_code)]
+
+use chrono::prelude::*;
+use handling::application::pb::NewCargoBooked;
+use handling::domain::handling::{Cargo, HandlingActivity, HandlingEvent, HandlingEventType};
+use handling::domain::itinerary::Itinerary;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub fn cargo(id: &str, destination: &str) -> Cargo {
+    Cargo {
+        tracking_id: id.to_string(),
+        origin: "SESTO".to_string(),
+        destination: destination.to_string(),
+        arrival_deadline: Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap(),
+        itinerary: Itinerary::default(),
+    }
+}
+
+// An event of cargo 001 in Stockholm, completed now.
+pub fn handling_event(event_type: HandlingEventType, voyage_number: &str) -> HandlingEvent {
+    HandlingEvent {
+        tracking_id: "001".to_string(),
+        activity: HandlingActivity {
+            r#type: event_type,
+            location: "SESTO".to_string(),
+            voyage_number: voyage_number.to_string(),
+        },
+        registration_time: Utc::now(),
+        completion_time: Utc::now(),
+        is_expected: true,
+    }
+}
+
+pub fn new_cargo_booked(tracking_id: &str) -> NewCargoBooked {
+    NewCargoBooked {
+        tracking_id: tracking_id.to_string(),
+        origin: "SESTO".to_string(),
+        destination: "CNHKG".to_string(),
+        arrival_deadline: None,
+        delivery: None,
+    }
+}
+
+/// A directory of the test's own, removed with everything in it when
+/// dropped, also when the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "handling-test-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => return TempDir(path),
+                // Left behind by an earlier run that got the same process id.
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => panic!("cannot create {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
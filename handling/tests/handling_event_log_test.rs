@@ -0,0 +1,61 @@
+mod common;
+
+use common::{handling_event, TempDir};
+use handling::domain::handling::{HandlingEventRepository, HandlingEventType, TransportStatus};
+use handling::infrastructure::handling_event_log::HandlingEventLog;
+use handling::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+#[test]
+fn replay() {
+    tokio_test::block_on(async {
+        let dir = TempDir::new();
+        let path = dir.join("handling.log");
+        {
+            let log = HandlingEventLog::open(&path).unwrap();
+            log.append(&handling_event(HandlingEventType::Receive, ""))
+                .await
+                .unwrap();
+            log.append(&handling_event(HandlingEventType::Load, "0100S"))
+                .await
+                .unwrap();
+            let res = log
+                .append(&handling_event(HandlingEventType::Load, "0100S"))
+                .await;
+            assert!(matches!(res, Err(Error::IllegalTransition { .. })));
+        }
+        let len = fs::metadata(&path).unwrap().len();
+
+        // A crash in the middle of a write leaves a torn record behind.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let log = HandlingEventLog::open(&path).unwrap();
+        let history = log.query_handling_history("001".to_string()).await.unwrap();
+        assert_eq!(history.handling_events().len(), 2);
+        assert_eq!(history.transport_status(), TransportStatus::OnboardCarrier);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        log.append(&handling_event(HandlingEventType::Unload, "0100S"))
+            .await
+            .unwrap();
+        let history = log.query_handling_history("001".to_string()).await.unwrap();
+        assert_eq!(history.handling_events().len(), 3);
+        drop(log);
+        let history = HandlingEventLog::open(&path)
+            .unwrap()
+            .query_handling_history("001".to_string())
+            .await
+            .unwrap();
+        assert_eq!(history.transport_status(), TransportStatus::InPort);
+
+        // Damage to a complete record is not repaired.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let res = HandlingEventLog::open(&path);
+        assert!(matches!(res, Err(Error::RepositoryError(_))));
+    });
+}
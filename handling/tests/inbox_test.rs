@@ -0,0 +1,189 @@
+mod common;
+
+use common::{cargo, new_cargo_booked};
+use handling::application::envelope::Envelope;
+use handling::application::inbox::InboxHandler;
+use handling::application::integration_events::{
+    CargoDestinationChangedEventHandler, EventHandler, NewCargoBookedEventHandler,
+};
+use handling::application::pb::CargoDestinationChanged;
+use handling::domain::handling::{Cargo, TrackingID};
+use handling::domain::inbox::CargoInbox;
+use handling::domain::Repository;
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+
+fn envelope(kind: &str, id: &str, sequence: u64) -> Envelope {
+    Envelope {
+        id: Some(id.to_string()),
+        sequence: Some(sequence),
+        ..Envelope::of_type(kind)
+    }
+}
+
+fn destination_changed(destination: &str) -> CargoDestinationChanged {
+    CargoDestinationChanged {
+        tracking_id: "001".to_string(),
+        destination: destination.to_string(),
+        version: 0,
+    }
+}
+
+async fn check_inbox<R>(inboxes: R)
+where
+    R: Repository<TrackingID, CargoInbox> + 'static,
+{
+    let cargos = InmemRepository::<TrackingID, Cargo>::new();
+    let booking = InboxHandler::new(
+        inboxes.clone(),
+        NewCargoBookedEventHandler::new(cargos.clone()),
+    );
+    let destination = InboxHandler::new(
+        inboxes.clone(),
+        CargoDestinationChangedEventHandler::new(cargos.clone()),
+    );
+
+    // Fails while the cargo is unknown, which is not recorded.
+    let res = destination
+        .handle_message(
+            destination_changed("AUMEL"),
+            envelope("CargoDestinationChanged", "m2", 2),
+        )
+        .await;
+    assert!(matches!(res, Err(Error::UnknownCargo(_))));
+
+    booking
+        .handle_message(new_cargo_booked("001"), envelope("NewCargoBooked", "m1", 1))
+        .await
+        .unwrap();
+    destination
+        .handle_message(
+            destination_changed("AUMEL"),
+            envelope("CargoDestinationChanged", "m2", 2),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        cargos.find("001".to_string()).await.unwrap().destination,
+        "AUMEL"
+    );
+
+    // An older change arriving late is skipped.
+    destination
+        .handle_message(
+            destination_changed("USNYC"),
+            envelope("CargoDestinationChanged", "m0", 1),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        cargos.find("001".to_string()).await.unwrap().destination,
+        "AUMEL"
+    );
+
+    // A redelivered change is skipped, even after the cargo changed since.
+    let mut cargo = cargos.find("001".to_string()).await.unwrap();
+    cargo.destination = "JNTKO".to_string();
+    cargos.store("001".to_string(), &cargo).await.unwrap();
+    let redelivered = Envelope {
+        sequence: None,
+        ..envelope("CargoDestinationChanged", "m2", 0)
+    };
+    destination
+        .handle_message(destination_changed("AUMEL"), redelivered)
+        .await
+        .unwrap();
+    assert_eq!(
+        cargos.find("001".to_string()).await.unwrap().destination,
+        "JNTKO"
+    );
+
+    let inbox = inboxes.find("001".to_string()).await.unwrap();
+    assert_eq!(inbox.message_ids, vec!["m1", "m2"]);
+    assert_eq!(inbox.sequences.get("CargoDestinationChanged"), Some(&2));
+    assert_eq!(inbox.sequences.get("NewCargoBooked"), Some(&1));
+}
+
+// Publishers like booking set no message ID and no sequence number.
+async fn check_inbox_without_ids<R>(inboxes: R)
+where
+    R: Repository<TrackingID, CargoInbox> + 'static,
+{
+    let cargos = InmemRepository::<TrackingID, Cargo>::new();
+    let destination = InboxHandler::new(
+        inboxes.clone(),
+        CargoDestinationChangedEventHandler::new(cargos.clone()),
+    );
+    let change = |destination: &str, version: u64| CargoDestinationChanged {
+        version,
+        ..destination_changed(destination)
+    };
+    cargos
+        .store("001".to_string(), &cargo("001", "CNHKG"))
+        .await
+        .unwrap();
+    let destination_of = || async { cargos.find("001".to_string()).await.unwrap().destination };
+    let plain = || Envelope::of_type("CargoDestinationChanged");
+
+    destination
+        .handle_message(change("AUMEL", 0), plain())
+        .await
+        .unwrap();
+    assert_eq!(destination_of().await, "AUMEL");
+
+    // A redelivery is recognized by its content.
+    let mut changed = cargos.find("001".to_string()).await.unwrap();
+    changed.destination = "JNTKO".to_string();
+    cargos.store("001".to_string(), &changed).await.unwrap();
+    destination
+        .handle_message(change("AUMEL", 0), plain())
+        .await
+        .unwrap();
+    assert_eq!(destination_of().await, "JNTKO");
+
+    // A change back to an earlier destination is not a redelivery.
+    destination
+        .handle_message(change("USNYC", 0), plain())
+        .await
+        .unwrap();
+    destination
+        .handle_message(change("AUMEL", 0), plain())
+        .await
+        .unwrap();
+    assert_eq!(destination_of().await, "AUMEL");
+
+    // The version in the payload outdates older changes.
+    destination
+        .handle_message(change("DEHAM", 5), plain())
+        .await
+        .unwrap();
+    destination
+        .handle_message(change("USNYC", 4), plain())
+        .await
+        .unwrap();
+    assert_eq!(destination_of().await, "DEHAM");
+
+    let inbox = inboxes.find("001".to_string()).await.unwrap();
+    assert!(inbox.message_ids.is_empty());
+    assert_eq!(inbox.sequences.get("CargoDestinationChanged"), Some(&5));
+    assert!(inbox.digests.contains_key("CargoDestinationChanged"));
+}
+
+#[test]
+fn skips_duplicate_and_stale_events() {
+    tokio_test::block_on(async {
+        check_inbox(InmemRepository::new()).await;
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_inbox(db.inboxes()).await;
+    });
+}
+
+#[test]
+fn skips_redelivered_events_without_ids() {
+    tokio_test::block_on(async {
+        check_inbox_without_ids(InmemRepository::new()).await;
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_inbox_without_ids(db.inboxes()).await;
+    });
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use common::new_cargo_booked;
+use handling::application::envelope::{Envelope, SCHEMA_VERSION, SOURCE};
+use handling::application::integration_events::{
+    CargoDestinationChangedEventHandler, EventHandler, NewCargoBookedEventHandler, SubscribeManager,
+};
+use handling::application::pb::{
+    CargoDestinationChanged, HandlingEvent as PbHandlingEvent, NewCargoBooked,
+};
+use handling::application::service::{Service, ServiceImpl};
+use handling::domain::handling::{Cargo, HandlingEventFactoryImpl, HandlingEventType, TrackingID};
+use handling::domain::{location, voyage, Repository};
+use handling::infrastructure::inmem_eventbus::InmemEventBus;
+use handling::infrastructure::inmem_outbox::InmemOutbox;
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::outbox_relay::OutboxRelay;
+use handling::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[test]
+fn delivers_and_records() {
+    tokio_test::block_on(async {
+        let cargos = InmemRepository::<TrackingID, Cargo>::new();
+        let mut bus = InmemEventBus::recording();
+        bus.subscribe::<NewCargoBooked, _>(NewCargoBookedEventHandler::new(cargos.clone()))
+            .await
+            .unwrap();
+        bus.subscribe::<CargoDestinationChanged, _>(CargoDestinationChangedEventHandler::new(
+            cargos.clone(),
+        ))
+        .await
+        .unwrap();
+
+        bus.publish(&new_cargo_booked("001")).await.unwrap();
+        bus.publish(&CargoDestinationChanged {
+            tracking_id: "001".to_string(),
+            destination: "AUMEL".to_string(),
+            version: 0,
+        })
+        .await
+        .unwrap();
+        let cargo = cargos.find("001".to_string()).await.unwrap();
+        assert_eq!(cargo.destination, "AUMEL");
+
+        // Handler errors are returned to the publisher.
+        let res = bus
+            .publish(&CargoDestinationChanged {
+                tracking_id: "002".to_string(),
+                destination: "AUMEL".to_string(),
+                version: 0,
+            })
+            .await;
+        assert!(matches!(res, Err(Error::UnknownCargo(_))));
+
+        let voyages = InmemRepository::new();
+        voyage::populate_repository(&voyages).await.unwrap();
+        let locations = InmemRepository::new();
+        location::store_sample_locations(&locations).await.unwrap();
+        let handling_events = InmemOutbox::new(InmemRepository::new());
+        let srv = ServiceImpl::new_service(
+            handling_events.clone(),
+            HandlingEventFactoryImpl::new(cargos, voyages, locations),
+        );
+        srv.register_handling_event(
+            Utc::now(),
+            "001".to_string(),
+            "".to_string(),
+            "SESTO".to_string(),
+            HandlingEventType::Receive,
+        )
+        .await
+        .unwrap();
+        let relay = OutboxRelay::new(handling_events, bus.clone());
+        assert_eq!(relay.relay_pending().await.unwrap(), 1);
+
+        let messages = bus.published_messages();
+        assert_eq!(messages.len(), 4);
+        let envelope = &messages[3].envelope;
+        assert_eq!(envelope.r#type, "HandlingEvent");
+        assert_eq!(envelope.source.as_deref(), Some(SOURCE));
+        assert_eq!(envelope.subject.as_deref(), Some("001"));
+        assert_eq!(envelope.correlation_id.as_deref(), Some("001"));
+        assert_eq!(envelope.schema_version, Some(SCHEMA_VERSION));
+        assert_ne!(envelope.id, messages[0].envelope.id);
+        // Brokers carry the envelope as attributes.
+        let attributes = envelope.attributes();
+        let read = Envelope::from_attributes("HandlingEvent", |name| {
+            attributes
+                .iter()
+                .find(|(attribute, _)| *attribute == name)
+                .map(|(_, value)| value.clone())
+        });
+        assert_eq!(read.id, envelope.id);
+        assert_eq!(read.correlation_id, envelope.correlation_id);
+        assert_eq!(read.schema_version, envelope.schema_version);
+        assert!(read.time.is_some());
+        let published = bus.published::<PbHandlingEvent>().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].tracking_id, "001");
+    });
+}
+
+// Counts the events of a cargo, for any event type.
+#[derive(Default)]
+struct CargoEvents {
+    count: AtomicUsize,
+}
+
+#[async_trait]
+impl EventHandler<NewCargoBooked> for CargoEvents {
+    async fn handle(&self, _e: NewCargoBooked) -> Result<(), Error> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventHandler<CargoDestinationChanged> for CargoEvents {
+    async fn handle(&self, _e: CargoDestinationChanged) -> Result<(), Error> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn shares_handlers() {
+    tokio_test::block_on(async {
+        let events = Arc::new(CargoEvents::default());
+        let mut bus = InmemEventBus::new();
+        bus.subscribe::<NewCargoBooked, _>(events.clone())
+            .await
+            .unwrap();
+        bus.subscribe::<CargoDestinationChanged, _>(events.clone())
+            .await
+            .unwrap();
+
+        bus.publish(&NewCargoBooked {
+            tracking_id: "001".to_string(),
+            ..NewCargoBooked::default()
+        })
+        .await
+        .unwrap();
+        bus.publish(&CargoDestinationChanged {
+            tracking_id: "001".to_string(),
+            destination: "AUMEL".to_string(),
+            version: 0,
+        })
+        .await
+        .unwrap();
+        assert_eq!(events.count.load(Ordering::SeqCst), 2);
+        // Only a recording bus keeps the published messages.
+        assert!(bus.published_messages().is_empty());
+    });
+}
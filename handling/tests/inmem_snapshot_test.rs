@@ -0,0 +1,103 @@
+mod common;
+
+use common::{cargo, handling_event, TempDir};
+use handling::domain::handling::{
+    HandlingEvent, HandlingEventCriteria, HandlingEventOutbox, HandlingEventRepository,
+    HandlingEventType, TransportStatus,
+};
+use handling::domain::{location, PageRequest, Repository};
+use handling::infrastructure::inmem_snapshot::InmemDatabase;
+use handling::Error;
+use std::fs;
+
+#[test]
+fn snapshot_round_trip() {
+    tokio_test::block_on(async {
+        let dir = TempDir::new();
+        let path = dir.join("snapshot.json");
+
+        let db = InmemDatabase::load(&path).unwrap();
+        assert!(db.cargos.find_all().await.unwrap().is_empty());
+        let cargo = cargo("001", "CNHKG");
+        db.cargos.store("001".to_string(), &cargo).await.unwrap();
+        location::store_sample_locations(&db.locations)
+            .await
+            .unwrap();
+        db.handling_events
+            .append(&handling_event(HandlingEventType::Receive, ""))
+            .await
+            .unwrap();
+        db.save(&path).await.unwrap();
+        // Saving over an existing snapshot replaces it.
+        db.save(&path).await.unwrap();
+
+        let restored = InmemDatabase::load(&path).unwrap();
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        fs::write(&path, "{\"cargos\":").unwrap();
+        let corrupted = InmemDatabase::load(&path);
+
+        assert_eq!(files.len(), 1, "temporary snapshot file is left behind");
+        let found = restored.cargos.find("001".to_string()).await.unwrap();
+        assert_eq!(found.arrival_deadline, cargo.arrival_deadline);
+        assert_eq!(
+            restored
+                .locations
+                .find("SESTO".to_string())
+                .await
+                .unwrap()
+                .name(),
+            "Stockholm"
+        );
+        let history = restored
+            .handling_events
+            .query_handling_history("001".to_string())
+            .await
+            .unwrap();
+        assert_eq!(history.transport_status(), TransportStatus::InPort);
+        let pending = restored.handling_events.pending(10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].event_id,
+            db.handling_events.pending(1).await.unwrap()[0].event_id
+        );
+        assert!(matches!(corrupted, Err(Error::RepositoryError(_))));
+    });
+}
+
+#[test]
+fn snapshot_keeps_outbox_with_events() {
+    // Events are appended on the worker threads while snapshots are taken.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let dir = TempDir::new();
+        let path = dir.join("snapshot.json");
+
+        let db = InmemDatabase::new();
+        let handling_events = db.handling_events.clone();
+        let appending = tokio::spawn(async move {
+            for i in 0..1000 {
+                handling_events
+                    .append(&HandlingEvent {
+                        tracking_id: format!("{:04}", i),
+                        ..handling_event(HandlingEventType::Receive, "")
+                    })
+                    .await
+                    .unwrap();
+            }
+        });
+        let mut snapshots = 0;
+        while !appending.is_finished() || snapshots == 0 {
+            db.save(&path).await.unwrap();
+            let restored = InmemDatabase::load(&path).unwrap();
+            let events = restored
+                .handling_events
+                .find_handling_events(HandlingEventCriteria::default(), PageRequest::first(1000))
+                .await
+                .unwrap();
+            let pending = restored.handling_events.pending(1000).await.unwrap();
+            assert_eq!(events.items.len(), pending.len());
+            snapshots += 1;
+        }
+        appending.await.unwrap();
+    });
+}
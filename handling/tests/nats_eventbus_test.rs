@@ -0,0 +1,189 @@
+#![cfg(feature = "nats")]
+
+use async_nats::jetstream::{self, consumer::pull, Context};
+use async_nats::HeaderMap;
+use bytes::Bytes;
+use futures_util::stream::StreamExt;
+use handling::application::integration_events::{
+    CargoDestinationChangedEventHandler, NewCargoBookedEventHandler, SubscribeManager,
+};
+use handling::application::pb::{CargoDestinationChanged, NewCargoBooked, TypeName};
+use handling::domain::handling::{Cargo, TrackingID};
+use handling::domain::Repository;
+use handling::infrastructure::eventbus::EventBusConfig;
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::nats_eventbus::{dead_letter_headers, NatsEventBus};
+use prost::Message;
+use std::time::Duration;
+
+fn nats_url() -> String {
+    std::env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string())
+}
+
+async fn publish<E: Message + TypeName>(jetstream: &Context, e: &E) {
+    let mut buf = vec![];
+    e.encode(&mut buf).unwrap();
+    jetstream
+        .publish(E::name(), Bytes::from(buf))
+        .await
+        .unwrap()
+        .await
+        .unwrap();
+}
+
+#[test]
+fn maps_dead_letter_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("Trace-Id", "42");
+    let headers = dead_letter_headers(Some(&headers), NewCargoBooked::name(), 3, "cargo not found");
+
+    let value = |name: &str| headers.get(name).map(|v| v.as_str().to_string());
+    assert_eq!(
+        value("Handling-Subject"),
+        Some(NewCargoBooked::name().to_string())
+    );
+    assert_eq!(value("Handling-Deliveries"), Some("3".to_string()));
+    assert_eq!(value("Handling-Error"), Some("cargo not found".to_string()));
+    assert_eq!(value("Trace-Id"), Some("42".to_string()));
+}
+
+#[test]
+#[ignore = "needs a local nats-server started with -js"]
+fn delivers_and_dead_letters() {
+    tokio_test::block_on(async {
+        let config = EventBusConfig {
+            max_retries: 1,
+            retry_delay: Duration::from_millis(100),
+            prefetch_count: 8,
+        };
+        let mut bus = NatsEventBus::new(&nats_url(), config).await.unwrap();
+        let cargos = InmemRepository::<TrackingID, Cargo>::new();
+        bus.subscribe::<NewCargoBooked, _>(NewCargoBookedEventHandler::new(cargos.clone()))
+            .await
+            .unwrap();
+        bus.subscribe::<CargoDestinationChanged, _>(CargoDestinationChangedEventHandler::new(
+            cargos.clone(),
+        ))
+        .await
+        .unwrap();
+
+        let jetstream = jetstream::new(async_nats::connect(nats_url()).await.unwrap());
+        let tracking_id = format!("NATS{}", std::process::id());
+        publish(
+            &jetstream,
+            &NewCargoBooked {
+                tracking_id: tracking_id.clone(),
+                origin: "SESTO".to_string(),
+                destination: "CNHKG".to_string(),
+                arrival_deadline: None,
+                delivery: None,
+            },
+        )
+        .await;
+        let mut booked = false;
+        for _ in 0..50 {
+            if cargos.find(tracking_id.clone()).await.is_ok() {
+                booked = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(booked);
+
+        // A change of an unknown cargo fails every time.
+        let unknown = CargoDestinationChanged {
+            tracking_id: format!("{}-unknown", tracking_id),
+            destination: "AUMEL".to_string(),
+            version: 0,
+        };
+        publish(&jetstream, &unknown).await;
+        let consumer = jetstream
+            .get_stream("shipping")
+            .await
+            .unwrap()
+            .create_consumer(pull::Config {
+                filter_subject: "handling.dead-letter".to_string(),
+                ..pull::Config::default()
+            })
+            .await
+            .unwrap();
+        let mut dead_letters = consumer.messages().await.unwrap();
+        let dead_lettered = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(message) = dead_letters.next().await {
+                let message = message.unwrap();
+                message.ack().await.unwrap();
+                let e = CargoDestinationChanged::decode(message.payload.clone()).unwrap();
+                if e.tracking_id == unknown.tracking_id {
+                    return message;
+                }
+            }
+            panic!("dead letters ended");
+        })
+        .await
+        .unwrap();
+        let headers = dead_lettered.headers.clone().unwrap();
+        assert_eq!(
+            headers.get("Handling-Subject").map(|v| v.as_str()),
+            Some(CargoDestinationChanged::name())
+        );
+        assert_eq!(
+            headers.get("Handling-Deliveries").map(|v| v.as_str()),
+            Some("2")
+        );
+    });
+}
+
+#[test]
+#[ignore = "needs a local nats-server started with -js"]
+fn orders_messages_of_a_cargo_across_types() {
+    tokio_test::block_on(async {
+        let mut bus = NatsEventBus::new(&nats_url(), EventBusConfig::default())
+            .await
+            .unwrap();
+        let cargos = InmemRepository::<TrackingID, Cargo>::new();
+        bus.subscribe::<NewCargoBooked, _>(NewCargoBookedEventHandler::new(cargos.clone()))
+            .await
+            .unwrap();
+        bus.subscribe::<CargoDestinationChanged, _>(CargoDestinationChangedEventHandler::new(
+            cargos.clone(),
+        ))
+        .await
+        .unwrap();
+
+        // The change fails unless the booking published before it is handled
+        // first, though they are messages of different subjects.
+        let jetstream = jetstream::new(async_nats::connect(nats_url()).await.unwrap());
+        let tracking_id = format!("ORDER{}", std::process::id());
+        publish(
+            &jetstream,
+            &NewCargoBooked {
+                tracking_id: tracking_id.clone(),
+                origin: "SESTO".to_string(),
+                destination: "CNHKG".to_string(),
+                arrival_deadline: None,
+                delivery: None,
+            },
+        )
+        .await;
+        publish(
+            &jetstream,
+            &CargoDestinationChanged {
+                tracking_id: tracking_id.clone(),
+                destination: "AUMEL".to_string(),
+                version: 0,
+            },
+        )
+        .await;
+        let mut changed = false;
+        for _ in 0..50 {
+            if let Ok(cargo) = cargos.find(tracking_id.clone()).await {
+                if cargo.destination == "AUMEL" {
+                    changed = true;
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(changed);
+    });
+}
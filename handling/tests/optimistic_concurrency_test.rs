@@ -0,0 +1,150 @@
+mod common;
+
+use common::{cargo, handling_event, new_cargo_booked};
+use handling::application::integration_events::{
+    CargoDestinationChangedEventHandler, EventHandler, NewCargoBookedEventHandler,
+};
+use handling::application::pb::CargoDestinationChanged;
+use handling::domain::handling::{
+    Cargo, HandlingEventRepository, HandlingEventType, HandlingHistory, TrackingID,
+};
+use handling::domain::{retry_on_conflict, Repository, MAX_UPDATE_ATTEMPTS};
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+use std::cell::Cell;
+
+async fn check_store_if_version<R: Repository<TrackingID, Cargo>>(cargos: R) {
+    let id = || "001".to_string();
+    assert_eq!(
+        cargos
+            .store_if_version(id(), &cargo("001", "CNHKG"), None)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(matches!(
+        cargos
+            .store_if_version(id(), &cargo("001", "CNHKG"), None)
+            .await,
+        Err(Error::VersionConflict(_))
+    ));
+    assert_eq!(
+        cargos
+            .store_if_version(id(), &cargo("001", "AUMEL"), Some(1))
+            .await
+            .unwrap(),
+        2
+    );
+    assert!(matches!(
+        cargos
+            .store_if_version(id(), &cargo("001", "USNYC"), Some(1))
+            .await,
+        Err(Error::VersionConflict(_))
+    ));
+    cargos.store(id(), &cargo("001", "SEGOT")).await.unwrap();
+
+    let found = cargos.find_versioned(id()).await.unwrap();
+    assert_eq!(found.version, 3);
+    assert_eq!(found.value.destination, "SEGOT");
+}
+
+async fn check_append_if_version<H: HandlingEventRepository>(handling_events: H) {
+    handling_events
+        .append_if_version(&handling_event(HandlingEventType::Receive, ""), 0)
+        .await
+        .unwrap();
+    let history = handling_events
+        .query_handling_history("001".to_string())
+        .await
+        .unwrap();
+    assert_eq!(history.version(), 1);
+    assert!(matches!(
+        handling_events
+            .append_if_version(&handling_event(HandlingEventType::Receive, ""), 0)
+            .await,
+        Err(Error::VersionConflict(_))
+    ));
+}
+
+#[test]
+fn rejected_first_append() {
+    tokio_test::block_on(async {
+        let handling_events = InmemRepository::<TrackingID, HandlingHistory>::new();
+        let claim = handling_event(HandlingEventType::Claim, "");
+        assert!(matches!(
+            handling_events.append_if_version(&claim, 0).await,
+            Err(Error::IllegalTransition { .. })
+        ));
+        assert!(matches!(
+            handling_events.append(&claim).await,
+            Err(Error::IllegalTransition { .. })
+        ));
+        assert!(!handling_events.exists("001".to_string()).await.unwrap());
+        assert!(handling_events.find_all().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn conditional_writes() {
+    tokio_test::block_on(async {
+        check_store_if_version(InmemRepository::new()).await;
+        check_append_if_version(InmemRepository::<TrackingID, HandlingHistory>::new()).await;
+
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_store_if_version(db.repository()).await;
+        check_append_if_version(db.repository::<TrackingID, HandlingHistory>()).await;
+    });
+}
+
+#[test]
+fn retries() {
+    tokio_test::block_on(async {
+        let attempts = Cell::new(0);
+        let res = retry_on_conflict(|| {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < MAX_UPDATE_ATTEMPTS {
+                    Err(Error::VersionConflict("001".to_string()))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), MAX_UPDATE_ATTEMPTS);
+
+        let attempts = Cell::new(0);
+        let res: Result<(), Error> = retry_on_conflict(|| {
+            attempts.set(attempts.get() + 1);
+            async { Err(Error::VersionConflict("001".to_string())) }
+        })
+        .await;
+        assert!(matches!(res, Err(Error::VersionConflict(_))));
+        assert_eq!(attempts.get(), MAX_UPDATE_ATTEMPTS);
+    });
+}
+
+#[test]
+fn redelivered_booking() {
+    tokio_test::block_on(async {
+        let cargos = InmemRepository::<TrackingID, Cargo>::new();
+        let booked = new_cargo_booked("001");
+        let booking = NewCargoBookedEventHandler::new(cargos.clone());
+        booking.handle(booked.clone()).await.unwrap();
+        CargoDestinationChangedEventHandler::new(cargos.clone())
+            .handle(CargoDestinationChanged {
+                tracking_id: "001".to_string(),
+                destination: "AUMEL".to_string(),
+                version: 0,
+            })
+            .await
+            .unwrap();
+        booking.handle(booked).await.unwrap();
+
+        let found = cargos.find_versioned("001".to_string()).await.unwrap();
+        assert_eq!(found.value.destination, "AUMEL");
+        assert_eq!(found.version, 2);
+    });
+}
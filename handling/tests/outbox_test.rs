@@ -0,0 +1,148 @@
+mod common;
+
+use async_trait::async_trait;
+use common::{handling_event, TempDir};
+use handling::application::integration_events::EventService;
+use handling::domain::handling::{
+    HandlingEvent, HandlingEventOutbox, HandlingEventRepository, HandlingEventType,
+};
+use handling::infrastructure::handling_event_log::HandlingEventLog;
+use handling::infrastructure::inmem_outbox::InmemOutbox;
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::outbox_relay::OutboxRelay;
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+use std::sync::{Arc, Mutex};
+
+async fn check_outbox<H>(handling_events: &H)
+where
+    H: HandlingEventRepository + HandlingEventOutbox,
+{
+    handling_events
+        .append(&handling_event(HandlingEventType::Receive, ""))
+        .await
+        .unwrap();
+    handling_events
+        .append_if_version(&handling_event(HandlingEventType::Load, "0100S"), 1)
+        .await
+        .unwrap();
+    // Rejected events are not published.
+    assert!(handling_events
+        .append(&handling_event(HandlingEventType::Load, "0100S"))
+        .await
+        .is_err());
+
+    let pending = handling_events.pending(10).await.unwrap();
+    assert_eq!(pending.len(), 2);
+    assert!(pending[0].id < pending[1].id);
+    assert_ne!(pending[0].event_id, pending[1].event_id);
+    assert_eq!(pending[0].event.activity.r#type, HandlingEventType::Receive);
+    assert_eq!(pending[1].event.activity.r#type, HandlingEventType::Load);
+    assert_eq!(handling_events.pending(1).await.unwrap().len(), 1);
+
+    handling_events.mark_sent(pending[0].id).await.unwrap();
+    let unsent = handling_events.pending(10).await.unwrap();
+    assert_eq!(unsent.len(), 1);
+    assert_eq!(unsent[0].event.activity.r#type, HandlingEventType::Load);
+    assert_eq!(unsent[0].event_id, pending[1].event_id);
+}
+
+#[test]
+fn recorded_with_events() {
+    tokio_test::block_on(async {
+        check_outbox(&InmemOutbox::new(InmemRepository::new())).await;
+
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_outbox(&db.repository()).await;
+
+        let dir = TempDir::new();
+        let path = dir.join("outbox.log");
+        let log = HandlingEventLog::open(&path).unwrap();
+        check_outbox(&log).await;
+        let event_id = log.pending(1).await.unwrap()[0].event_id.clone();
+        drop(log);
+        // What was sent is remembered across restarts, and so are the ids of
+        // the unsent messages.
+        let pending = HandlingEventLog::open(&path)
+            .unwrap()
+            .pending(10)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, 2);
+        assert_eq!(pending[0].event_id, event_id);
+    });
+}
+
+// Publishes until the given number of events was published, fails after.
+#[derive(Clone)]
+struct Publisher {
+    published: Arc<Mutex<Vec<HandlingEvent>>>,
+    // The message ids of all attempts, failed ones included.
+    attempts: Arc<Mutex<Vec<String>>>,
+    capacity: Arc<Mutex<usize>>,
+}
+
+#[async_trait]
+impl EventService for Publisher {
+    async fn cargo_was_handled(&self, e: HandlingEvent, id: String) -> Result<(), Error> {
+        self.attempts.lock().unwrap().push(id);
+        let mut capacity = self.capacity.lock().unwrap();
+        if *capacity == 0 {
+            return Err(Error::RepositoryError("broker unavailable".to_string()));
+        }
+        *capacity -= 1;
+        self.published.lock().unwrap().push(e);
+        Ok(())
+    }
+}
+
+#[test]
+fn relay() {
+    tokio_test::block_on(async {
+        let handling_events = InmemOutbox::new(InmemRepository::new());
+        for (event_type, voyage_number) in [
+            (HandlingEventType::Receive, ""),
+            (HandlingEventType::Load, "0100S"),
+            (HandlingEventType::Unload, "0100S"),
+        ] {
+            handling_events
+                .append(&handling_event(event_type, voyage_number))
+                .await
+                .unwrap();
+        }
+        let publisher = Publisher {
+            published: Arc::default(),
+            attempts: Arc::default(),
+            capacity: Arc::new(Mutex::new(1)),
+        };
+        let relay = OutboxRelay::new(handling_events.clone(), publisher.clone());
+
+        assert!(relay.relay_pending().await.is_err());
+        assert_eq!(handling_events.pending(10).await.unwrap().len(), 2);
+
+        *publisher.capacity.lock().unwrap() = 10;
+        assert_eq!(relay.relay_pending().await.unwrap(), 2);
+        assert_eq!(relay.relay_pending().await.unwrap(), 0);
+        // The message that failed is published again with the same id.
+        let attempts = publisher.attempts.lock().unwrap().clone();
+        assert_eq!(attempts.len(), 4);
+        assert_eq!(attempts[1], attempts[2]);
+        assert_ne!(attempts[2], attempts[3]);
+        let published: Vec<_> = publisher
+            .published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.activity.r#type.clone())
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                HandlingEventType::Receive,
+                HandlingEventType::Load,
+                HandlingEventType::Unload
+            ]
+        );
+    });
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use async_trait::async_trait;
+use common::new_cargo_booked;
+use handling::application::envelope::Envelope;
+use handling::application::integration_events::{
+    CargoDestinationChangedEventHandler, EventHandler, NewCargoBookedEventHandler, SubscribeManager,
+};
+use handling::application::parking::Parking;
+use handling::application::pb::{CargoDestinationChanged, NewCargoBooked};
+use handling::domain::handling::{Cargo, TrackingID};
+use handling::domain::parking::ParkingLot;
+use handling::domain::Repository;
+use handling::infrastructure::inmem_eventbus::InmemEventBus;
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn destination_changed(tracking_id: &str) -> CargoDestinationChanged {
+    CargoDestinationChanged {
+        tracking_id: tracking_id.to_string(),
+        destination: "AUMEL".to_string(),
+        version: 0,
+    }
+}
+
+async fn check_parking<R>(lots: R)
+where
+    R: Repository<TrackingID, ParkingLot> + 'static,
+{
+    let cargos = InmemRepository::<TrackingID, Cargo>::new();
+    let spawner = InmemEventBus::new().ordered_spawner();
+    let parking = Parking::new(lots.clone(), Duration::from_secs(3600));
+    let booking = parking.replaying(NewCargoBookedEventHandler::new(cargos.clone()));
+    let destination = parking.parking(CargoDestinationChangedEventHandler::new(cargos.clone()));
+
+    // The change arrives ahead of the booking and is parked.
+    let envelope = Envelope {
+        id: Some("m2".to_string()),
+        ..Envelope::of_type("CargoDestinationChanged")
+    };
+    destination
+        .handle_message(destination_changed("001"), envelope)
+        .await
+        .unwrap();
+    let lot = lots.find("001".to_string()).await.unwrap();
+    assert_eq!(lot.parked.len(), 1);
+    assert_eq!(lot.parked[0].kind, "CargoDestinationChanged");
+
+    // Sweeping keeps it parked while the cargo is unknown.
+    parking.replay_all(&spawner).await.unwrap();
+    assert_eq!(lots.find("001".to_string()).await.unwrap().parked.len(), 1);
+
+    // The booking replays it.
+    booking.handle(new_cargo_booked("001")).await.unwrap();
+    assert_eq!(
+        cargos.find("001".to_string()).await.unwrap().destination,
+        "AUMEL"
+    );
+    let lot = lots.find("001".to_string()).await.unwrap();
+    assert!(lot.parked.is_empty());
+    assert!(lot.dead_letters.is_empty());
+
+    // A change of a cargo that is never booked expires.
+    let expiring = Parking::new(lots.clone(), Duration::from_secs(0));
+    let destination = expiring.parking(CargoDestinationChangedEventHandler::new(cargos.clone()));
+    destination
+        .handle(destination_changed("002"))
+        .await
+        .unwrap();
+    expiring.replay_all(&spawner).await.unwrap();
+    let lot = lots.find("002".to_string()).await.unwrap();
+    assert!(lot.parked.is_empty());
+    assert_eq!(lot.dead_letters.len(), 1);
+    assert_eq!(lot.dead_letters[0].event.kind, "CargoDestinationChanged");
+    assert!(cargos.find("002".to_string()).await.is_err());
+}
+
+#[test]
+fn replays_events_of_cargos_booked_late() {
+    tokio_test::block_on(async {
+        check_parking(InmemRepository::new()).await;
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_parking(db.parking_lots()).await;
+    });
+}
+
+// Counts the events its handler handled. Handling takes a while, which
+// leaves time for overlapping replays.
+struct Counting<EH> {
+    handler: EH,
+    handled: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl<EH> EventHandler<CargoDestinationChanged> for Counting<EH>
+where
+    EH: EventHandler<CargoDestinationChanged>,
+{
+    async fn handle(&self, e: CargoDestinationChanged) -> Result<(), Error> {
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        self.handler.handle(e).await?;
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn sweeps_in_order_with_bookings() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let cargos = InmemRepository::<TrackingID, Cargo>::new();
+        let lots = InmemRepository::<TrackingID, ParkingLot>::new();
+        let parking = Parking::new(lots.clone(), Duration::from_secs(3600));
+        let handled = Arc::new(AtomicUsize::new(0));
+        let mut bus = InmemEventBus::new();
+        bus.subscribe::<NewCargoBooked, _>(
+            parking.replaying(NewCargoBookedEventHandler::new(cargos.clone())),
+        )
+        .await
+        .unwrap();
+        bus.subscribe::<CargoDestinationChanged, _>(parking.parking(Counting {
+            handler: CargoDestinationChangedEventHandler::new(cargos.clone()),
+            handled: handled.clone(),
+        }))
+        .await
+        .unwrap();
+        let spawner = bus.ordered_spawner();
+
+        // The sweeper and the booking both find the parked change, only one
+        // of them may replay it.
+        for i in 0..20 {
+            let tracking_id = format!("{:03}", i);
+            bus.publish(&destination_changed(&tracking_id))
+                .await
+                .unwrap();
+            let sweeping = tokio::spawn({
+                let parking = parking.clone();
+                let spawner = spawner.clone();
+                async move { parking.replay_all(&spawner).await }
+            });
+            bus.publish(&new_cargo_booked(&tracking_id)).await.unwrap();
+            sweeping.await.unwrap().unwrap();
+            assert_eq!(handled.load(Ordering::SeqCst), i + 1);
+            let lot = lots.find(tracking_id.clone()).await.unwrap();
+            assert!(lot.parked.is_empty());
+            assert!(lot.dead_letters.is_empty());
+            assert_eq!(cargos.find(tracking_id).await.unwrap().destination, "AUMEL");
+        }
+    });
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use common::cargo;
+use handling::domain::handling::{
+    Cargo, CargoCriteria, CargoRepository, HandlingActivity, HandlingEvent, HandlingEventCriteria,
+    HandlingEventRepository, HandlingEventType, HandlingHistory, TrackingID,
+};
+use handling::domain::{Page, PageRequest};
+use handling::infrastructure::inmem_repository::InmemRepository;
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+
+fn start() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap()
+}
+
+fn handling_event(
+    id: &str,
+    event_type: HandlingEventType,
+    location: &str,
+    voyage_number: &str,
+    hours: i64,
+) -> HandlingEvent {
+    HandlingEvent {
+        tracking_id: id.to_string(),
+        activity: HandlingActivity {
+            r#type: event_type,
+            location: location.to_string(),
+            voyage_number: voyage_number.to_string(),
+        },
+        registration_time: start() + Duration::hours(hours),
+        completion_time: start() + Duration::hours(hours),
+        is_expected: true,
+    }
+}
+
+fn tracking_ids(page: &Page<Cargo>) -> Vec<&str> {
+    page.items.iter().map(|c| c.tracking_id.as_str()).collect()
+}
+
+async fn check_cargo_queries<R: CargoRepository>(cargos: R) {
+    for (id, destination) in &[("003", "AUMEL"), ("001", "CNHKG"), ("002", "AUMEL")] {
+        cargos
+            .store(id.to_string(), &cargo(id, destination))
+            .await
+            .unwrap();
+    }
+    assert!(cargos.exists("001".to_string()).await.unwrap());
+    assert!(!cargos.exists("004".to_string()).await.unwrap());
+
+    let first = cargos.find_page(PageRequest::first(2)).await.unwrap();
+    assert_eq!(tracking_ids(&first), ["001", "002"]);
+    let request = PageRequest::first(2).next(&first).unwrap();
+    let second = cargos.find_page(request.clone()).await.unwrap();
+    assert_eq!(tracking_ids(&second), ["003"]);
+    assert!(request.next(&second).is_none());
+
+    let to_melbourne = CargoCriteria {
+        destination: Some("AUMEL".to_string()),
+        ..CargoCriteria::default()
+    };
+    let page = cargos
+        .find_by(to_melbourne, PageRequest::first(10))
+        .await
+        .unwrap();
+    assert_eq!(tracking_ids(&page), ["002", "003"]);
+
+    cargos.delete("002".to_string()).await.unwrap();
+    assert!(!cargos.exists("002".to_string()).await.unwrap());
+    assert!(matches!(
+        cargos.delete("002".to_string()).await,
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(
+        cargos.find_page(PageRequest::first(0)).await,
+        Err(Error::InvalidField { field: "limit", .. })
+    ));
+}
+
+async fn check_handling_event_queries<H: HandlingEventRepository>(handling_events: H) {
+    let events = [
+        handling_event("001", HandlingEventType::Receive, "SESTO", "", 0),
+        handling_event("002", HandlingEventType::Receive, "SESTO", "", 1),
+        handling_event("001", HandlingEventType::Load, "SESTO", "0100S", 2),
+        handling_event("003", HandlingEventType::Receive, "CNHKG", "", 3),
+        handling_event("002", HandlingEventType::Load, "SESTO", "0100S", 4),
+        handling_event("001", HandlingEventType::Unload, "AUMEL", "0100S", 5),
+    ];
+    for e in events.iter() {
+        handling_events.append(e).await.unwrap();
+    }
+
+    let in_stockholm = HandlingEventCriteria {
+        location: Some("SESTO".to_string()),
+        completed_from: Some(start() + Duration::hours(1)),
+        completed_to: Some(start() + Duration::hours(5)),
+        ..HandlingEventCriteria::default()
+    };
+    let request = PageRequest::first(2);
+    let first = handling_events
+        .find_handling_events(in_stockholm.clone(), request.clone())
+        .await
+        .unwrap();
+    let request = request.next(&first).unwrap();
+    let second = handling_events
+        .find_handling_events(in_stockholm, request.clone())
+        .await
+        .unwrap();
+    assert!(request.next(&second).is_none());
+    let found: Vec<(&str, i64)> = first
+        .items
+        .iter()
+        .chain(second.items.iter())
+        .map(|e| {
+            let hours = (e.completion_time - start()).num_hours();
+            (e.tracking_id.as_str(), hours)
+        })
+        .collect();
+    assert_eq!(found, [("002", 1), ("001", 2), ("002", 4)]);
+
+    let loads = HandlingEventCriteria {
+        event_type: Some(HandlingEventType::Load),
+        ..HandlingEventCriteria::default()
+    };
+    let page = handling_events
+        .find_handling_events(loads, PageRequest::first(10))
+        .await
+        .unwrap();
+    assert_eq!(page.items.len(), 2);
+    assert!(page.next_cursor.is_none());
+
+    let malformed = PageRequest {
+        cursor: Some("yesterday".to_string()),
+        limit: 10,
+    };
+    assert!(matches!(
+        handling_events
+            .find_handling_events(HandlingEventCriteria::default(), malformed)
+            .await,
+        Err(Error::InvalidField {
+            field: "cursor",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn inmem_queries() {
+    tokio_test::block_on(async {
+        check_cargo_queries(InmemRepository::<TrackingID, Cargo>::new()).await;
+        check_handling_event_queries(InmemRepository::<TrackingID, HandlingHistory>::new()).await;
+    });
+}
+
+#[test]
+fn sqlite_queries() {
+    tokio_test::block_on(async {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        check_cargo_queries(db.repository::<TrackingID, Cargo>()).await;
+        check_handling_event_queries(db.repository::<TrackingID, HandlingHistory>()).await;
+    });
+}
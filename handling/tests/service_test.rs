@@ -1,39 +1,75 @@
-use async_trait::async_trait;
+mod common;
+
 use chrono::prelude::*;
-use handling::application::integration_events::EventService;
+use common::cargo;
+use handling::application::grpc_server::HandlingServiceImpl;
+use handling::application::pb::{
+    BadRequest, HandlingEventType as PbHandlingEventType, HandlingService, NewCargoBooked,
+    RegisterHandlingEventRequest, RpcStatus,
+};
 use handling::application::service::{Service, ServiceImpl};
 use handling::domain::handling::{
-    Cargo, HandlingEvent, HandlingEventFactoryImpl, HandlingEventType,
+    Cargo, HandlingEventFactoryImpl, HandlingEventRepository, HandlingEventType, HandlingHistory,
+    TransportStatus,
 };
+use handling::domain::itinerary::{Itinerary, Leg};
+use handling::domain::location::{Location, UNLocode};
+use handling::domain::voyage::{Voyage, VoyageNumber};
 use handling::domain::{location, voyage, Repository};
 use handling::infrastructure::inmem_repository::InmemRepository;
 use handling::Error;
+use prost::Message;
+use prost_types::Timestamp;
+use std::convert::TryFrom;
+use tonic::{Code, Request};
+
+type TestService = ServiceImpl<
+    InmemRepository<String, HandlingHistory>,
+    HandlingEventFactoryImpl<
+        InmemRepository<String, Cargo>,
+        InmemRepository<VoyageNumber, Voyage>,
+        InmemRepository<UNLocode, Location>,
+    >,
+>;
+
+// prepare dependencies and create service instance
+async fn new_service(cargos: &[Cargo]) -> (TestService, InmemRepository<String, HandlingHistory>) {
+    let cargo_repository = InmemRepository::new();
+    for c in cargos {
+        cargo_repository
+            .store(c.tracking_id.clone(), c)
+            .await
+            .unwrap();
+    }
+    let voyages = InmemRepository::new();
+    voyage::populate_repository(&voyages).await.unwrap();
+    let locations = InmemRepository::new();
+    location::store_sample_locations(&locations).await.unwrap();
+    let handling_events = InmemRepository::new();
+    let event_factory = HandlingEventFactoryImpl::new(cargo_repository, voyages, locations);
+    let srv = ServiceImpl::new_service(handling_events.clone(), event_factory);
+    (srv, handling_events)
+}
 
 #[test]
 fn service() {
     tokio_test::block_on(async {
-        // prerare dependencies
-        let cargos = InmemRepository::new();
-        cargos
-            .store(
+        let (srv, _) = new_service(&[Cargo {
+            origin: "AUMEL".to_string(),
+            ..cargo("001", "SESTO")
+        }])
+        .await;
+        let res = srv
+            .register_handling_event(
+                Utc::now(),
                 "001".to_string(),
-                &Cargo {
-                    tracking_id: "001".to_string(),
-                    origin: "AUMEL".to_string(),
-                    destination: "SESTO".to_string(),
-                    arrival_deadline: Utc::now(),
-                },
+                "".to_string(),
+                "SESTO".to_string(),
+                HandlingEventType::Receive,
             )
-            .unwrap();
-        let voyages = InmemRepository::new();
-        voyage::populate_repository(&voyages).unwrap();
-        let locations = InmemRepository::new();
-        location::store_sample_locations(&locations).unwrap();
-        let handling_events = InmemRepository::new();
-        let event_factory = HandlingEventFactoryImpl::new(cargos, voyages, locations);
-
-        // create service instance
-        let srv = ServiceImpl::new_service(handling_events, event_factory, MocEventService {});
+            .await;
+        assert!(res.is_ok());
+
         let res = srv
             .register_handling_event(
                 Utc::now(),
@@ -49,11 +85,324 @@ fn service() {
     });
 }
 
-struct MocEventService;
+#[test]
+fn handling_history() {
+    tokio_test::block_on(async {
+        let (srv, handling_events) = new_service(&[cargo("001", "AUMEL")]).await;
+
+        // The second customs check is registered after the third one.
+        let received = Utc.with_ymd_and_hms(2021, 6, 1, 0, 0, 0).unwrap();
+        let second = Utc.with_ymd_and_hms(2021, 6, 2, 0, 0, 0).unwrap();
+        let third = Utc.with_ymd_and_hms(2021, 6, 3, 0, 0, 0).unwrap();
+        for (completed, event_type) in [
+            (received, HandlingEventType::Receive),
+            (third, HandlingEventType::Customs),
+            (second, HandlingEventType::Customs),
+        ] {
+            srv.register_handling_event(
+                completed,
+                "001".to_string(),
+                "".to_string(),
+                "SESTO".to_string(),
+                event_type,
+            )
+            .await
+            .unwrap();
+        }
+
+        let history = handling_events
+            .query_handling_history("001".to_string())
+            .await
+            .unwrap();
+        let completed: Vec<_> = history
+            .handling_events()
+            .iter()
+            .map(|e| e.completion_time)
+            .collect();
+        assert_eq!(completed, vec![received, second, third]);
+        assert!(history
+            .handling_events()
+            .iter()
+            .all(|e| e.registration_time >= third));
+        assert_eq!(
+            history
+                .most_recently_completed_event()
+                .unwrap()
+                .completion_time,
+            third
+        );
+
+        let empty = handling_events
+            .query_handling_history("002".to_string())
+            .await
+            .unwrap();
+        assert!(empty.handling_events().is_empty());
+    });
+}
+
+#[test]
+fn expected_handling_events() {
+    tokio_test::block_on(async {
+        let itinerary = Itinerary {
+            legs: vec![Leg {
+                voyage_number: "0100S".to_string(),
+                load_location: "SESTO".to_string(),
+                unload_location: "CNHKG".to_string(),
+                load_time: Utc::now(),
+                unload_time: Utc::now(),
+            }],
+        };
+        let (srv, _) = new_service(&[
+            Cargo {
+                itinerary: itinerary.clone(),
+                ..cargo("001", "CNHKG")
+            },
+            Cargo {
+                itinerary,
+                ..cargo("002", "CNHKG")
+            },
+        ])
+        .await;
 
-#[async_trait]
-impl EventService for MocEventService {
-    async fn cargo_was_handled(&self, _: HandlingEvent) -> Result<(), Error> {
-        Ok(())
-    }
+        let cases = vec![
+            ("001", "", "SESTO", HandlingEventType::Receive, true),
+            ("001", "0100S", "SESTO", HandlingEventType::Load, true),
+            ("001", "0100S", "CNHKG", HandlingEventType::Unload, true),
+            ("001", "", "CNHKG", HandlingEventType::Claim, true),
+            ("002", "", "CNHKG", HandlingEventType::Receive, false),
+            ("002", "0200T", "CNHKG", HandlingEventType::Load, false),
+            ("002", "0100S", "USNYC", HandlingEventType::Unload, false),
+            ("002", "", "USNYC", HandlingEventType::Claim, false),
+        ];
+        for (id, voyage_number, location, event_type, expected) in cases {
+            let e = srv
+                .register_handling_event(
+                    Utc::now(),
+                    id.to_string(),
+                    voyage_number.to_string(),
+                    location.to_string(),
+                    event_type,
+                )
+                .await
+                .unwrap();
+            assert_eq!(e.is_expected, expected, "{:?}", e.activity);
+        }
+    });
+}
+
+#[test]
+fn illegal_transitions() {
+    tokio_test::block_on(async {
+        let (srv, handling_events) = new_service(&[cargo("001", "CNHKG")]).await;
+        let register = |voyage_number: &str, event_type| {
+            srv.register_handling_event(
+                Utc::now(),
+                "001".to_string(),
+                voyage_number.to_string(),
+                "SESTO".to_string(),
+                event_type,
+            )
+        };
+
+        let res = register("", HandlingEventType::Claim).await;
+        assert!(matches!(
+            res,
+            Err(Error::IllegalTransition {
+                status: TransportStatus::NotReceived,
+                ..
+            })
+        ));
+
+        register("", HandlingEventType::Receive).await.unwrap();
+        register("0100S", HandlingEventType::Load).await.unwrap();
+        let res = register("0100S", HandlingEventType::Load).await;
+        assert!(matches!(
+            res,
+            Err(Error::IllegalTransition {
+                status: TransportStatus::OnboardCarrier,
+                ..
+            })
+        ));
+        let res = register("", HandlingEventType::Customs).await;
+        assert!(res.is_err());
+
+        let history = handling_events
+            .query_handling_history("001".to_string())
+            .await
+            .unwrap();
+        assert_eq!(history.handling_events().len(), 2);
+        assert_eq!(history.transport_status(), TransportStatus::OnboardCarrier);
+    });
+}
+
+#[test]
+fn field_requirements() {
+    tokio_test::block_on(async {
+        let (srv, _) = new_service(&[cargo("001", "CNHKG")]).await;
+        let cases = vec![
+            ("", "", "SESTO", HandlingEventType::Receive, "id"),
+            ("001", "", "", HandlingEventType::Receive, "un_locode"),
+            (
+                "001",
+                "",
+                "SESTO",
+                HandlingEventType::NotHandled,
+                "event_type",
+            ),
+            ("001", "", "SESTO", HandlingEventType::Load, "voyage_number"),
+            (
+                "001",
+                "",
+                "SESTO",
+                HandlingEventType::Unload,
+                "voyage_number",
+            ),
+            (
+                "001",
+                "0100S",
+                "SESTO",
+                HandlingEventType::Receive,
+                "voyage_number",
+            ),
+            (
+                "001",
+                "0100S",
+                "SESTO",
+                HandlingEventType::Claim,
+                "voyage_number",
+            ),
+            (
+                "001",
+                "0100S",
+                "SESTO",
+                HandlingEventType::Customs,
+                "voyage_number",
+            ),
+        ];
+        for (id, voyage_number, location, event_type, invalid_field) in cases {
+            let res = srv
+                .register_handling_event(
+                    Utc::now(),
+                    id.to_string(),
+                    voyage_number.to_string(),
+                    location.to_string(),
+                    event_type,
+                )
+                .await;
+            match res {
+                Err(Error::InvalidField { field, .. }) => assert_eq!(field, invalid_field),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    });
+}
+
+#[test]
+fn grpc_status_codes() {
+    tokio_test::block_on(async {
+        let (srv, _) = new_service(&[cargo("001", "CNHKG")]).await;
+        let gservice = HandlingServiceImpl::new(srv);
+        let cases = vec![
+            (
+                "002",
+                "",
+                "SESTO",
+                PbHandlingEventType::Receive,
+                Code::NotFound,
+                Some("id"),
+            ),
+            (
+                "001",
+                "",
+                "XXXXX",
+                PbHandlingEventType::Receive,
+                Code::NotFound,
+                Some("un_locode"),
+            ),
+            (
+                "001",
+                "",
+                "SESTO",
+                PbHandlingEventType::Load,
+                Code::InvalidArgument,
+                Some("voyage_number"),
+            ),
+            (
+                "001",
+                "",
+                "SESTO",
+                PbHandlingEventType::Claim,
+                Code::FailedPrecondition,
+                None,
+            ),
+        ];
+        for (id, voyage_number, location, event_type, code, field) in cases {
+            let req = RegisterHandlingEventRequest {
+                completed: None,
+                id: id.to_string(),
+                voyage_number: voyage_number.to_string(),
+                un_locode: location.to_string(),
+                event_type: event_type as i32,
+            };
+            let status = gservice
+                .register_handling_event(Request::new(req))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), code, "{}", status.message());
+
+            let violations: Vec<_> = if status.details().is_empty() {
+                vec![]
+            } else {
+                let details = RpcStatus::decode(status.details()).unwrap();
+                let bad_request = BadRequest::decode(details.details[0].value.as_slice()).unwrap();
+                bad_request
+                    .field_violations
+                    .into_iter()
+                    .map(|v| v.field)
+                    .collect()
+            };
+            assert_eq!(violations, field.into_iter().collect::<Vec<_>>());
+        }
+    });
+}
+
+#[test]
+fn out_of_range_timestamps() {
+    tokio_test::block_on(async {
+        let (srv, _) = new_service(&[cargo("001", "CNHKG")]).await;
+        let gservice = HandlingServiceImpl::new(srv);
+        let req = RegisterHandlingEventRequest {
+            completed: Some(Timestamp {
+                seconds: i64::MAX,
+                nanos: 0,
+            }),
+            id: "001".to_string(),
+            voyage_number: "".to_string(),
+            un_locode: "SESTO".to_string(),
+            event_type: PbHandlingEventType::Receive as i32,
+        };
+        let status = gservice
+            .register_handling_event(Request::new(req))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let booked = NewCargoBooked {
+            tracking_id: "002".to_string(),
+            origin: "SESTO".to_string(),
+            destination: "CNHKG".to_string(),
+            arrival_deadline: Some(Timestamp {
+                seconds: 0,
+                nanos: -1,
+            }),
+            delivery: None,
+        };
+        assert!(matches!(
+            Cargo::try_from(booked),
+            Err(Error::InvalidField {
+                field: "arrival_deadline",
+                ..
+            })
+        ));
+    });
 }
@@ -0,0 +1,120 @@
+mod common;
+
+use chrono::prelude::*;
+use common::{handling_event, TempDir};
+use handling::domain::handling::{
+    Cargo, HandlingEventRepository, HandlingEventType, HandlingHistory, TrackingID, TransportStatus,
+};
+use handling::domain::itinerary::{Itinerary, Leg};
+use handling::domain::location::{Location, UNLocode};
+use handling::domain::voyage::{Voyage, VoyageNumber};
+use handling::domain::{location, voyage, Repository};
+use handling::infrastructure::sqlite_repository::SqliteDatabase;
+use handling::Error;
+
+#[test]
+fn cargos() {
+    tokio_test::block_on(async {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        let cargos = db.repository::<TrackingID, Cargo>();
+        let deadline = Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap();
+        let mut cargo = Cargo {
+            tracking_id: "001".to_string(),
+            origin: "SESTO".to_string(),
+            destination: "CNHKG".to_string(),
+            arrival_deadline: deadline,
+            itinerary: Itinerary::default(),
+        };
+        cargos
+            .store(cargo.tracking_id.clone(), &cargo)
+            .await
+            .unwrap();
+
+        cargo.destination = "AUMEL".to_string();
+        cargo.itinerary = Itinerary {
+            legs: vec![Leg {
+                voyage_number: "0100S".to_string(),
+                load_location: "SESTO".to_string(),
+                unload_location: "AUMEL".to_string(),
+                load_time: deadline,
+                unload_time: deadline,
+            }],
+        };
+        cargos
+            .store(cargo.tracking_id.clone(), &cargo)
+            .await
+            .unwrap();
+
+        let found = cargos.find("001".to_string()).await.unwrap();
+        assert_eq!(found.destination, "AUMEL");
+        assert_eq!(found.arrival_deadline, deadline);
+        assert_eq!(found.itinerary.legs.len(), 1);
+        assert_eq!(found.itinerary.legs[0].unload_location, "AUMEL");
+        assert_eq!(cargos.find_all().await.unwrap().len(), 1);
+        assert!(matches!(
+            cargos.find("002".to_string()).await,
+            Err(Error::NotFound(_))
+        ));
+    });
+}
+
+#[test]
+fn voyages_and_locations() {
+    tokio_test::block_on(async {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        let voyages = db.repository::<VoyageNumber, Voyage>();
+        voyage::populate_repository(&voyages).await.unwrap();
+        // Populating twice must not fail, the server does it on every start.
+        voyage::populate_repository(&voyages).await.unwrap();
+        assert_eq!(voyages.find_all().await.unwrap().len(), 5);
+        assert!(voyages.find("0100S".to_string()).await.is_ok());
+
+        let locations = db.repository::<UNLocode, Location>();
+        location::store_sample_locations(&locations).await.unwrap();
+        location::store_sample_locations(&locations).await.unwrap();
+        assert_eq!(
+            locations.find("SESTO".to_string()).await.unwrap().name(),
+            "Stockholm"
+        );
+        assert!(matches!(
+            locations.find("XXXXX".to_string()).await,
+            Err(Error::NotFound(_))
+        ));
+    });
+}
+
+#[test]
+fn handling_events_survive_reopening() {
+    tokio_test::block_on(async {
+        let dir = TempDir::new();
+        let path = dir.join("handling.db");
+        let url = format!("sqlite://{}", path.display());
+        {
+            let db = SqliteDatabase::open(&url).unwrap();
+            let handling_events = db.repository::<TrackingID, HandlingHistory>();
+            handling_events
+                .append(&handling_event(HandlingEventType::Receive, ""))
+                .await
+                .unwrap();
+            handling_events
+                .append(&handling_event(HandlingEventType::Load, "0100S"))
+                .await
+                .unwrap();
+            let res = handling_events
+                .append(&handling_event(HandlingEventType::Load, "0100S"))
+                .await;
+            assert!(matches!(res, Err(Error::IllegalTransition { .. })));
+        }
+
+        let db = SqliteDatabase::open(&url).unwrap();
+        let history = db
+            .repository::<TrackingID, HandlingHistory>()
+            .query_handling_history("001".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(history.handling_events().len(), 2);
+        assert_eq!(history.transport_status(), TransportStatus::OnboardCarrier);
+        assert_eq!(history.handling_events()[1].activity.voyage_number, "0100S");
+    });
+}